@@ -1,6 +1,8 @@
 //! Actor AI components and systems.
 
-use std::collections::HashSet;
+use std::io;
+use std::io::Read;
+use std::io::Write;
 
 use rand::seq::IteratorRandom as _;
 use rand::seq::SliceRandom as _;
@@ -11,13 +13,29 @@ use legion::world::SubWorld;
 use legion::Entity;
 
 use crate::actor::player::Player;
+use crate::actor::base::Digger;
+use crate::actor::base::MoveDelay;
+use crate::actor::base::Oriented;
 use crate::actor::base::Position;
+use crate::actor::base::Swimmer;
 use crate::actor::base::Tangible;
+use crate::actor::light::LightMap;
 use crate::geo::graph;
+use crate::geo::graph::DistanceMap;
+use crate::geo::Dir;
 use crate::geo::Point;
+use crate::geo::PointSet;
+use crate::geo::Rect;
 use crate::geo::fov;
+use crate::map::tile::Mobility;
 use crate::map::Floor;
-use crate::map::Tile;
+use crate::map::Memory;
+use crate::map::Tiles;
+use crate::rng::Rng;
+use crate::rng::Stream;
+use crate::save;
+use crate::save::Decode;
+use crate::save::Encode;
 use crate::timing::SystemTimer;
 
 /// Describes the current state of the AI turn.
@@ -30,6 +48,21 @@ pub enum TurnMode {
   Running,
 }
 
+impl Encode for TurnMode {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    (*self == TurnMode::Running).encode(w)
+  }
+}
+
+impl Decode for TurnMode {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    match bool::decode(r)? {
+      true => Ok(TurnMode::Running),
+      false => Ok(TurnMode::Waiting),
+    }
+  }
+}
+
 /// System: Ends an AI turn at the end of a frame.
 #[legion::system]
 pub fn end_turn(#[resource] mode: &mut TurnMode) {
@@ -40,11 +73,17 @@ pub fn end_turn(#[resource] mode: &mut TurnMode) {
 ///
 /// When the [`pathfind()`] system is installed, every `Pathfind` entity with a
 /// [`Position`] will A* to its goal point. The path will only be recalculated
-/// if the entity encounters a barrier in the way.
+/// if the entity encounters a barrier in the way, or if the [`Floor`] changes
+/// underneath it.
 pub struct Pathfind {
   script: Vec<Box<dyn Tactic>>,
   goal: Option<Point>,
   path: Vec<Point>,
+  // The floor revision `path` was computed at.
+  revision: u64,
+  // Whether `goal` is something to run away from, rather than towards; see
+  // `Tactic::flees()`.
+  fleeing: bool,
 }
 
 impl Pathfind {
@@ -55,9 +94,19 @@ impl Pathfind {
       script,
       goal: None,
       path: Vec::new(),
+      revision: 0,
+      fleeing: false,
     }
   }
 
+  /// Forgets this `Pathfind`'s current goal and path, such as when the entity
+  /// is moved somewhere else entirely.
+  pub fn reset(&mut self) {
+    self.goal = None;
+    self.path.clear();
+    self.fleeing = false;
+  }
+
   /// Re-runs this `Pathfind`'s goal-finding script.
   ///
   /// This function goes through each [`Tactic`] in the script, trying to find
@@ -67,15 +116,23 @@ impl Pathfind {
     fov: Option<&Fov>,
     world: &mut SubWorld,
     floor: &Floor,
+    rng: &mut Stream,
   ) {
+    // Something is only worth running away from while it's still there, so
+    // goals to flee from are worked out afresh every time.
+    if self.fleeing {
+      self.reset();
+    }
+
     for tactic in &mut self.script {
       if self.goal.is_some() && !tactic.run_always() {
         continue;
       }
 
-      if let Some(goal) = tactic.generate_goal(fov, world, floor) {
+      if let Some(goal) = tactic.generate_goal(fov, world, floor, rng) {
         let requires_repath = self.goal != Some(goal);
         self.goal = Some(goal);
+        self.fleeing = tactic.flees();
         if requires_repath {
           self.path.clear();
         }
@@ -84,42 +141,113 @@ impl Pathfind {
     }
   }
 
+  /// Saves this `Pathfind`, including the state of its script.
+  pub fn save(&self, w: &mut save::Writer) -> io::Result<()> {
+    self.script.len().encode(w)?;
+    for tactic in &self.script {
+      tactic.name().encode(w)?;
+      tactic.save(w)?;
+    }
+    self.goal.encode(w)?;
+    self.path.encode(w)?;
+    self.fleeing.encode(w)
+  }
+
+  /// Loads a `Pathfind` written by [`Pathfind::save()`].
+  pub fn load(r: &mut save::Reader) -> io::Result<Self> {
+    let len = usize::decode(r)?;
+    let mut script = Vec::new();
+    for _ in 0..len {
+      let name = String::decode(r)?;
+      script.push(load_tactic(&name, r)?);
+    }
+    Ok(Pathfind {
+      script,
+      goal: Decode::decode(r)?,
+      path: Decode::decode(r)?,
+      revision: 0,
+      fleeing: Decode::decode(r)?,
+    })
+  }
+
   /// Recomputes the path towards this `Pathfind`'s goal.
+  ///
+  /// Paths are weighted by [`TileDef::step_cost()`], so they steer around
+  /// rough and hazardous terrain where they can. `mobility` is how the entity
+  /// gets around.
+  ///
+  /// [`TileDef::step_cost()`]: crate::map::tile::TileDef::step_cost
   pub fn repath(
     &mut self,
     current: Point,
     floor: &Floor,
-    _occupied: &HashSet<Point>,
+    tiles: &Tiles,
+    mobility: Mobility,
+    _occupied: &PointSet,
   ) {
     if let Some(goal) = self.goal {
-      self.path = graph::manhattan_a_star(current, goal, |p| {
-        // !occupied.contains(&p) &&
-        floor
-          .chunk(p)
-          .map(|c| *c.tile(p) == Tile::Ground)
-          .unwrap_or(false)
-      })
-      .unwrap_or(Vec::new());
+      self.revision = floor.revision();
+      let cost = |p| tiles.get(floor.tile(p)).step_cost(mobility);
+      self.path = graph::a_star(
+        current,
+        goal,
+        |_, p| {
+          // !occupied.contains(p) &&
+          cost(p).is_some()
+        },
+        |a, b| (a - b).manhattan() as f64 * cost(b).unwrap_or(f64::INFINITY),
+        |p| (p - goal).manhattan() as f64,
+      )
+      .unwrap_or_default();
     }
   }
 
   /// Computes the next point that the entity should walk to, if one is
   /// available.
+  ///
+  /// If the goal is one of the goals of `approach`, and the entity is within
+  /// its bounds, this simply walks downhill on it rather than pathfinding.
+  /// If the entity is fleeing from its goal instead, it walks downhill on
+  /// `flee`, which must have been built from `approach`.
   pub fn next_pos(
     &mut self,
     current: Point,
     floor: &Floor,
-    occupied: &HashSet<Point>,
+    tiles: &Tiles,
+    mobility: Mobility,
+    approach: &DistanceMap,
+    flee: Option<&DistanceMap>,
+    occupied: &PointSet,
   ) -> Option<Point> {
-    if self.goal.is_none() || self.goal == Some(current) {
-      self.goal = None;
-      return None;
+    let goal = match self.goal {
+      Some(goal) if goal != current => goal,
+      _ => {
+        self.goal = None;
+        return None;
+      }
+    };
+
+    if self.fleeing {
+      self.path.clear();
+      return flee?.downhill(current, occupied);
+    }
+
+    if approach.get(goal) == Some(0.0) {
+      if let Some(next) = approach.downhill(current, occupied) {
+        self.path.clear();
+        return Some(next);
+      }
     }
 
     // Check that the cached path is valid, which is given by our current
-    // position being the last element. If it isn't, we re-path.
-    if Some(&current) != self.path.last() {
-      self.repath(current, floor, occupied);
+    // position being the last element, and by none of the tiles along it
+    // having changed since. If it isn't, we re-path.
+    let is_stale = self
+      .path
+      .iter()
+      .any(|&p| floor.changed_since(self.revision, p));
+    if Some(&current) != self.path.last() || is_stale {
+      self.repath(current, floor, tiles, mobility, occupied);
     }
 
     self.path.pop();
@@ -143,43 +271,91 @@ pub trait Tactic: Send + Sync {
     false
   }
 
+  /// The name this tactic is saved under; see [`load_tactic()`].
+  fn name(&self) -> &'static str;
+
+  /// The entity this tactic is currently going after, if any.
+  fn target(&self) -> Option<Entity> {
+    None
+  }
+
+  /// Whether the goals this tactic generates are to be run away from, rather
+  /// than towards.
+  ///
+  /// Actors can only flee from players, since they find their way using the
+  /// shared map of distances to them; see [`pathfind()`].
+  fn flees(&self) -> bool {
+    false
+  }
+
+  /// Saves any state this tactic carries between steps.
+  fn save(&self, _: &mut save::Writer) -> io::Result<()> {
+    Ok(())
+  }
+
   /// Attempts to generate a new goal, using the provided information.
   ///
   /// `fov` is the FOV of the current actor.
   /// `world` has acccess to all components that are readable by [`pathfind()`],
   /// except for [`Pathfind`] components.
+  /// `rng` is the AI's random stream; tactics must not use any other source of
+  /// randomness, so that AI behavior is reproducible from the seed.
   fn generate_goal(
     &mut self,
     fov: Option<&Fov>,
     world: &mut SubWorld,
     floor: &Floor,
+    rng: &mut Stream,
   ) -> Option<Point>;
 }
 
 // `Tactic` is object safe!
 impl dyn Tactic {}
 
+/// Loads a tactic saved with [`Tactic::save()`], given its name.
+///
+/// Every [`Tactic`] must be listed here in order to be saved and loaded.
+pub fn load_tactic(
+  name: &str,
+  r: &mut save::Reader,
+) -> io::Result<Box<dyn Tactic>> {
+  match name {
+    "chase" => Ok(Box::new(Chase {
+      target: r.entity()?,
+    })),
+    "wander" => Ok(Box::new(Wander)),
+    "flee" => Ok(Box::new(Flee)),
+    _ => Err(save::invalid(format!("unknown tactic `{}`", name))),
+  }
+}
+
 /// A tactic that causes the entity to aimlessly wander around the floor.
 ///
 /// Every time a new goal is needed, it picks a random point of a random room,
 /// and A*s to it.
 pub struct Wander;
 impl Tactic for Wander {
+  fn name(&self) -> &'static str {
+    "wander"
+  }
+
   fn generate_goal(
     &mut self,
     _: Option<&Fov>,
     _: &mut SubWorld,
     floor: &Floor,
+    rng: &mut Stream,
   ) -> Option<Point> {
-    let mut rng = rand::thread_rng();
-    let room = floor.rooms().choose(&mut rng)?;
-    room.points().choose(&mut rng)
+    let room = floor.rooms().choose(rng)?;
+    room.rect.points().choose(rng)
   }
 }
 
 /// A tactic for chasing a player in-view of the entity.
 ///
-/// This goal is executed
+/// Only players in the entity's [`Fov`] are noticed, so an entity with a
+/// [`VisionCone`] won't notice players sneaking up behind it, and gives up the
+/// chase as soon as it loses sight of them.
 pub struct Chase {
   target: Option<Entity>,
 }
@@ -193,11 +369,21 @@ impl Tactic for Chase {
   fn run_always(&self) -> bool {
     true
   }
+  fn name(&self) -> &'static str {
+    "chase"
+  }
+  fn target(&self) -> Option<Entity> {
+    self.target
+  }
+  fn save(&self, w: &mut save::Writer) -> io::Result<()> {
+    w.entity(self.target)
+  }
   fn generate_goal(
     &mut self,
     fov: Option<&Fov>,
     world: &mut SubWorld,
     _: &Floor,
+    _: &mut Stream,
   ) -> Option<Point> {
     // First, check whether the entity we're chasing (if any) is currently in
     // sight. If not, delete it.
@@ -211,7 +397,7 @@ impl Tactic for Chase {
       let pos = query.get(world, entity).ok()?;
 
       if let Some(fov) = fov {
-        if fov.visible.contains(&pos.0) {
+        if fov.visible.contains(pos.0) {
           Some(entity)
         } else {
           None
@@ -232,7 +418,7 @@ impl Tactic for Chase {
       {
         for (entity, pos) in chunk.into_iter_entities() {
           if let Some(fov) = fov {
-            if fov.visible.contains(&pos.0) {
+            if fov.visible.contains(pos.0) {
               self.target = Some(entity);
               break 'outer;
             }
@@ -253,17 +439,58 @@ impl Tactic for Chase {
   }
 }
 
+/// A tactic for running away from players in view of the entity.
+///
+/// Rather than heading for any one spot, the entity keeps stepping downhill on
+/// a map built with [`DistanceMap::flee()`], so it won't back itself into a
+/// corner when there is a way past the players to somewhere farther off.
+pub struct Flee;
+impl Tactic for Flee {
+  fn run_always(&self) -> bool {
+    true
+  }
+  fn name(&self) -> &'static str {
+    "flee"
+  }
+  fn flees(&self) -> bool {
+    true
+  }
+  fn generate_goal(
+    &mut self,
+    fov: Option<&Fov>,
+    world: &mut SubWorld,
+    _: &Floor,
+    _: &mut Stream,
+  ) -> Option<Point> {
+    // As with `Chase`, an entity with no Fov sees everything.
+    <&Position>::query()
+      .filter(component::<Player>())
+      .iter(world)
+      .map(|p| p.0)
+      .find(|&p| match fov {
+        Some(fov) => fov.visible.contains(p),
+        None => true,
+      })
+  }
+}
+
 /// System: Steps forward the AI for each every [`Pathfind`] entity.
 #[legion::system]
 #[read_component(Fov)]
 #[read_component(Player)]
 #[read_component(Tangible)]
+#[read_component(Swimmer)]
+#[read_component(Digger)]
 #[write_component(Position)]
+#[write_component(Oriented)]
 #[write_component(Pathfind)]
+#[write_component(MoveDelay)]
 pub fn pathfind(
   world: &mut SubWorld,
-  #[resource] floor: &Floor,
+  #[resource] floor: &mut Floor,
+  #[resource] tiles: &Tiles,
   #[resource] mode: &TurnMode,
+  #[resource] rng: &mut Rng,
   #[resource] timer: &SystemTimer,
 ) {
   let _t = timer.start("actor::ai::pathfind()");
@@ -275,25 +502,97 @@ pub fn pathfind(
   // does *not* mutate positions.
   let mut query = <(&mut Pathfind, Option<&Fov>)>::query();
   let (mut query_world, mut rest) = world.split_for_query(&query);
+  let rng = rng.stream("ai");
+  let mut is_anyone_fleeing = false;
   for (pf, fov) in query.iter_mut(&mut query_world) {
-    pf.refresh_goal(fov, &mut rest, floor);
+    pf.refresh_goal(fov, &mut rest, floor, rng);
+    is_anyone_fleeing |= pf.fleeing;
   }
 
+  // Most AIs are after a player, so rather than having each of them pathfind
+  // separately, they share a single map for approaching the players.
+  let players = <&Position>::query()
+    .filter(component::<Player>())
+    .iter(world)
+    .map(|p| p.0)
+    .collect::<Vec<_>>();
+  let cost = |p| tiles.get(floor.tile(p)).step_cost(Mobility::default());
+  let can_walk = |p| cost(p).is_some();
+  let distance =
+    |a: Point, b| (a - b).manhattan() as f64 * cost(b).unwrap_or(f64::INFINITY);
+  let approach = DistanceMap::new(
+    approach_bounds(&players),
+    players.iter().copied(),
+    can_walk,
+    distance,
+  );
+  // The same goes for running away from them, though few AIs ever do.
+  let flee = if is_anyone_fleeing {
+    Some(approach.flee(FLEE_SCALE, can_walk, distance))
+  } else {
+    None
+  };
+
   let mut occupied = <&Position>::query()
-    //.filter(component::<&Tangible>())
+    .filter(component::<Tangible>())
     .iter(world)
     .map(|p| p.0)
-    .collect::<HashSet<_>>();
+    .collect::<PointSet>();
 
   // Now, step forward all of the pathfinding AIs. This requires mutating
   // positions, but does not require splitting the world.
-  let mut q = <(&mut Pathfind, &mut Position, Option<&Tangible>)>::query();
-  for (pf, pos, tangible) in q.iter_mut(world) {
-    if let Some(p) = pf.next_pos(pos.0, floor, &occupied) {
-      let is_walkable = floor
-        .chunk(p)
-        .map(|c| *c.tile(p) == Tile::Ground)
-        .unwrap_or(false);
+  let mut q = <(
+    &mut Pathfind,
+    &mut Position,
+    Option<&Tangible>,
+    Option<&Swimmer>,
+    Option<&Digger>,
+    Option<&mut MoveDelay>,
+    Option<&mut Oriented>,
+  )>::query();
+  for (pf, pos, tangible, swimmer, digger, mut delay, dir) in
+    q.iter_mut(world)
+  {
+    // Actors wading through rough terrain have to wait their turn.
+    if let Some(MoveDelay(turns)) = delay.as_deref_mut() {
+      if *turns > 0 {
+        *turns -= 1;
+        continue;
+      }
+    }
+
+    let mobility = Mobility {
+      swims: swimmer.is_some(),
+      digs: digger.is_some(),
+    };
+    let next = pf.next_pos(
+      pos.0,
+      floor,
+      tiles,
+      mobility,
+      &approach,
+      flee.as_ref(),
+      &occupied,
+    );
+    if let Some(p) = next {
+      // Actors look where they're going.
+      if let (Some(Oriented(d)), Some(towards)) =
+        (dir, Dir::from_point(p - pos.0))
+      {
+        *d = towards;
+      }
+
+      // If there's a door or a wall in the way, we spend this turn opening or
+      // digging through it, and step through on the next one.
+      if !occupied.contains(p)
+        && (floor.open(tiles, p) || (mobility.digs && floor.dig(tiles, p)))
+      {
+        pf.path.push(pos.0);
+        pf.revision = floor.revision();
+        continue;
+      }
+      let def = tiles.get(floor.tile(p));
+      let can_enter = def.can_enter(mobility);
 
       // As an optimization, we assume that there is only ever one actor in a
       // given position, so we remove pos.0 and add p, though only if this
@@ -302,56 +601,460 @@ pub fn pathfind(
       // We try this a few times to make sure it converges, since there are
       // situations where a previous move invalidates a path.
       for _ in 0..3 {
-        if is_walkable && !occupied.contains(&p) {
+        if can_enter && !occupied.contains(p) {
           if tangible.is_some() {
-            occupied.remove(&pos.0);
+            occupied.remove(pos.0);
             occupied.insert(p);
           }
           pos.0 = p;
+          if let Some(MoveDelay(turns)) = delay.as_deref_mut() {
+            *turns = def.delay();
+          }
           break;
         } else {
-          pf.repath(pos.0, floor, &occupied);
+          pf.repath(pos.0, floor, tiles, mobility, &occupied);
         }
       }
     }
   }
 }
 
+/// How far around the players the shared approach map extends.
+const APPROACH_RADIUS: i64 = 40;
+
+/// How much farther than the players fleeing actors are willing to run past
+/// them to get away; see [`DistanceMap::flee()`].
+const FLEE_SCALE: f64 = 1.2;
+
+/// Computes the region covered by the shared approach map for players at
+/// `players`.
+fn approach_bounds(players: &[Point]) -> Rect {
+  let (min, max) = match players.first() {
+    Some(&p) => players.iter().fold((p, p), |(min, max), &p| {
+      (
+        Point::new(min.x().min(p.x()), min.y().min(p.y())),
+        Point::new(max.x().max(p.x()), max.y().max(p.y())),
+      )
+    }),
+    None => return Rect::with_dims(0, 0),
+  };
+
+  let radius = Point::new(APPROACH_RADIUS, APPROACH_RADIUS);
+  Rect::new(min - radius, max + radius + Point::new(1, 1))
+}
+
 /// Component: An actor with a field-of-view.
 pub struct Fov {
   /// The radius of the FOV range.
   pub range: Point<i64>,
+  /// The algorithm used to decide what is in view.
+  pub algorithm: &'static dyn fov::Algorithm,
   /// The set of points that are currently visible.
-  pub visible: HashSet<Point<i64>>,
-  /// The set of points that have been seen.
-  pub seen: HashSet<Point<i64>>,
+  pub visible: PointSet,
+  /// What every point that has been seen looked like when it was last seen,
+  /// if this actor remembers what it has seen; see [`Fov::with_memory()`].
+  pub memory: Option<Memory>,
+  /// If set, the actor can only see in a cone in front of it, as given by its
+  /// [`Oriented`] component.
+  pub cone: Option<VisionCone>,
+  // The line of sight computed the last time the FOV was out of date; this is
+  // not saved.
+  sight: Option<Sight>,
+}
+
+/// Every point within an actor's line of sight, lit or not, along with what
+/// it was computed from.
+struct Sight {
+  pos: Point,
+  range: Point<i64>,
+  algorithm: &'static str,
+  cone: Option<fov::Cone>,
+  revision: u64,
+  points: PointSet,
+  // The light map revision the visible set was last computed at.
+  lights: u64,
 }
 
+impl Fov {
+  /// Creates a new `Fov` that hasn't seen anything yet.
+  pub fn new(
+    range: Point<i64>,
+    algorithm: &'static dyn fov::Algorithm,
+  ) -> Self {
+    Fov {
+      range,
+      algorithm,
+      visible: PointSet::new(),
+      memory: None,
+      cone: None,
+      sight: None,
+    }
+  }
+
+  /// Returns a copy of this `Fov` that remembers what it has seen.
+  ///
+  /// Only actors that need to know what is out of sight, like the player,
+  /// should do this, since memories are kept up to date every turn and saved.
+  pub fn with_memory(mut self) -> Self {
+    self.memory = Some(Memory::new());
+    self
+  }
+
+  /// Returns a copy of this `Fov` that can only see within `cone`.
+  pub fn with_cone(mut self, cone: VisionCone) -> Self {
+    self.cone = Some(cone);
+    self
+  }
 
+  /// Forgets this `Fov`'s cached line of sight, so that it is recomputed from
+  /// scratch. This is needed when the entity is moved to another [`Floor`]
+  /// entirely, since revisions of different floors can't be compared.
+  pub fn reset(&mut self) {
+    self.sight = None;
+  }
+}
 
+impl Encode for Fov {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.range.encode(w)?;
+    self.algorithm.name().encode(w)?;
+    self.visible.encode(w)?;
+    self.memory.encode(w)?;
+    self.cone.encode(w)
+  }
+}
+
+impl Decode for Fov {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let range = Decode::decode(r)?;
+    let name = String::decode(r)?;
+    let algorithm = fov::by_name(&name).ok_or_else(|| {
+      save::invalid(format!("unknown FOV algorithm `{}`", name))
+    })?;
+    Ok(Fov {
+      range,
+      algorithm,
+      visible: Decode::decode(r)?,
+      memory: Decode::decode(r)?,
+      cone: Decode::decode(r)?,
+      sight: None,
+    })
+  }
+}
+
+/// How far to either side of the direction it faces an actor with a [`Fov`]
+/// can see.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct VisionCone {
+  /// How far to either side of the actor's facing it can see, in radians.
+  pub half_width: f64,
+  /// The radius of the area around the actor that it can see in every
+  /// direction, shaped like the [`Fov`]'s range.
+  pub peripheral: Point<i64>,
+}
+
+impl Encode for VisionCone {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.half_width.encode(w)?;
+    self.peripheral.encode(w)
+  }
+}
+
+impl Decode for VisionCone {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    Ok(VisionCone {
+      half_width: Decode::decode(r)?,
+      peripheral: Decode::decode(r)?,
+    })
+  }
+}
+
+/// System: Recomputes what every actor with a [`Fov`] can see.
+///
+/// Seeing a tile takes both a line of sight to it and some light falling on
+/// it; tiles that are dark are neither visible nor remembered.
+///
+/// Lines of sight are expensive to compute, so they are only recomputed when
+/// the actor moves, its range or algorithm changes, or a chunk of the floor
+/// within its range changes. Otherwise, only the lighting is rechecked, and
+/// only for tiles in those regions of the [`LightMap`] where some tile has
+/// become lit or gone dark.
+///
+/// An actor whose `Fov` has a [`VisionCone`] only sees what is in front of it
+/// if it is [`Oriented`]; otherwise the cone is ignored.
 #[legion::system(for_each)]
 #[read_component(Position)]
+#[read_component(Oriented)]
 #[write_component(Fov)]
 pub fn update_fov(
   &Position(pos): &Position,
   fov: &mut Fov,
+  dir: Option<&Oriented>,
   #[resource] floor: &Floor,
+  #[resource] tiles: &Tiles,
+  #[resource] lights: &LightMap,
   #[resource] timer: &SystemTimer,
 ) {
   let _t = timer.start("actor::ai::update_fov()");
-  fov.visible.clear();
-  fov::milazzo(
-    pos,
-    fov.range,
-    &mut |p| {
-      floor
-        .chunk(p)
-        .map(|c| *c.tile(p) != Tile::Ground)
-        .unwrap_or(true)
-    },
-    &mut |p| {
-      fov.visible.insert(p);
-      fov.seen.insert(p);
-    },
-  );
-}
\ No newline at end of file
+  let bounds = Rect::new(pos - fov.range, pos + fov.range + Point::new(1, 1));
+  let cone = match (fov.cone, dir) {
+    (Some(cone), Some(&Oriented(d))) => Some(fov::Cone {
+      facing: d.to_point(),
+      half_width: cone.half_width,
+      peripheral: cone.peripheral,
+    }),
+    _ => None,
+  };
+  let is_current = |sight: &Sight| {
+    sight.pos == pos
+      && sight.range == fov.range
+      && sight.algorithm == fov.algorithm.name()
+      && sight.cone == cone
+      && !floor.changed_within(sight.revision, bounds)
+  };
+  let mut changes = None;
+  if !matches!(&fov.sight, Some(sight) if is_current(sight)) {
+    let mut points = PointSet::new();
+    let is_opaque = &mut |p| tiles.get(floor.tile(p)).is_opaque();
+    let ignite = &mut |p| {
+      points.insert(p);
+    };
+    match cone {
+      Some(cone) => fov
+        .algorithm
+        .compute_in_cone(pos, fov.range, cone, is_opaque, ignite),
+      None => fov.algorithm.compute(pos, fov.range, is_opaque, ignite),
+    }
+    fov.sight = Some(Sight {
+      pos,
+      range: fov.range,
+      algorithm: fov.algorithm.name(),
+      cone,
+      revision: floor.revision(),
+      points,
+      lights: lights.revision(),
+    });
+  } else if let Some(sight) = &fov.sight {
+    let rects = lights
+      .changes_within(sight.lights, bounds)
+      .collect::<Vec<_>>();
+    if rects.is_empty() {
+      // Neither the line of sight nor the lighting has changed, so neither
+      // has what is visible.
+      return;
+    }
+    changes = Some(rects);
+  }
+
+  let Fov {
+    visible,
+    memory,
+    sight,
+    ..
+  } = fov;
+  let sight = match sight {
+    Some(sight) => sight,
+    None => return,
+  };
+  sight.lights = lights.revision();
+  if changes.is_none() {
+    visible.clear();
+  }
+  let mut recheck = |p| {
+    if lights.is_lit(p) {
+      visible.insert(p);
+      if let Some(memory) = memory {
+        memory.insert(p, floor.tile(p));
+      }
+    } else {
+      visible.remove(p);
+    }
+  };
+  match changes {
+    Some(rects) => {
+      for rect in rects {
+        sight.points.iter_in(rect).for_each(&mut recheck);
+      }
+    }
+    None => sight.points.iter().for_each(recheck),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use legion::EntityStore as _;
+  use legion::Resources;
+  use legion::World;
+  use rand::RngCore as _;
+
+  use crate::map::dungeon::Dungeon;
+  use crate::map::dungeon::Stairs;
+  use crate::map::Prefabs;
+  use crate::map::Tile;
+  use crate::save::Registry;
+
+  fn registry() -> Registry {
+    let mut registry = Registry::new();
+    registry
+      .component::<Position>("position")
+      .component::<Fov>("fov")
+      .component_with("pathfind", Pathfind::save, Pathfind::load)
+      .component_with("player", |_: &Player, _| Ok(()), |_| Ok(Player))
+      .resource::<Rng>("rng")
+      .resource::<TurnMode>("turn_mode");
+    registry
+  }
+
+  fn resources(floor: Option<Floor>) -> Resources {
+    let mut resources = Resources::default();
+    resources.insert(Tiles::builtin());
+    resources.insert(Prefabs::builtin());
+    resources.insert(Dungeon::new());
+    if let Some(floor) = floor {
+      resources.insert(floor);
+    }
+    resources
+  }
+
+  fn player(world: &World) -> Entity {
+    let players = <Entity>::query()
+      .filter(component::<Player>())
+      .iter(world)
+      .copied()
+      .collect::<Vec<_>>();
+    assert_eq!(players.len(), 1);
+    players[0]
+  }
+
+  fn travel(world: &mut World, resources: &mut Resources, stairs: Stairs) {
+    resources.get_mut::<Dungeon>().unwrap().take_stairs(stairs);
+    Dungeon::travel(world, resources);
+  }
+
+  #[test]
+  fn game_survives_save_and_load() {
+    // A room on the first level, with a monster chasing the player around a
+    // detour that pathfinding would never pick on its own.
+    let stairs = Point::new(-3, 0);
+    let start = Point::new(3, 0);
+    let path = [(-2, 0), (-1, 1), (0, 2), (1, 2), (2, 1), (3, 0)]
+      .iter()
+      .map(|&(x, y)| Point::new(x, y))
+      .collect::<Vec<_>>();
+    let mut floor = Floor::new();
+    floor.add_room(Rect::with_dims(12, 8).centered_on(Point::zero()));
+    *floor.chunk_mut(stairs).tile_mut(stairs) = Tile::StairsDown;
+
+    let mut world = World::default();
+    let player = world.push((
+      Player,
+      Position(stairs),
+      Fov::new(Point::new(20, 10), &fov::Milazzo).with_memory(),
+    ));
+    world.push((
+      Position(start),
+      Pathfind {
+        script: vec![
+          Box::new(Chase {
+            target: Some(player),
+          }),
+          Box::new(Wander),
+        ],
+        goal: Some(path[0]),
+        path: path.clone(),
+        revision: floor.revision(),
+        fleeing: false,
+      },
+    ));
+
+    let mut resources = resources(Some(floor));
+    resources.insert(Rng::new(42));
+    resources.insert(TurnMode::Waiting);
+
+    // Head downstairs, leaving the monster behind on a stashed level, and
+    // look around a bit.
+    travel(&mut world, &mut resources, Stairs::Down);
+    {
+      let mut entry = world.entry(player).unwrap();
+      let fov = entry.get_component_mut::<Fov>().unwrap();
+      for x in 0..5 {
+        fov.visible.insert(Point::new(x, -x));
+      }
+      fov.memory.as_mut().unwrap().extend(vec![
+        (Point::new(-40, 7), Tile::Wall),
+        (Point::new(33, -2), Tile::Ground),
+      ]);
+    }
+    *resources.get_mut::<TurnMode>().unwrap() = TurnMode::Running;
+    resources.get_mut::<Rng>().unwrap().stream("ai").next_u64();
+
+    let registry = registry();
+    let mut buf = Vec::new();
+    Dungeon::save(&world, &resources, &registry, &mut buf).unwrap();
+    let mut expected_ai =
+      resources.get_mut::<Rng>().unwrap().stream("ai").clone();
+
+    let mut loaded = World::default();
+    let mut loaded_resources = self::resources(None);
+    Dungeon::load(
+      &mut loaded,
+      &mut loaded_resources,
+      &registry,
+      &mut buf.as_slice(),
+    )
+    .unwrap();
+
+    assert_eq!(
+      *loaded_resources.get::<TurnMode>().unwrap(),
+      TurnMode::Running
+    );
+    {
+      let mut rng = loaded_resources.get_mut::<Rng>().unwrap();
+      let ai = rng.stream("ai");
+      for _ in 0..10 {
+        assert_eq!(ai.next_u64(), expected_ai.next_u64());
+      }
+    }
+
+    let new_player = self::player(&loaded);
+    {
+      let old = world.entry_ref(player).unwrap();
+      let old = old.get_component::<Fov>().unwrap();
+      let new = loaded.entry_ref(new_player).unwrap();
+      let new = new.get_component::<Fov>().unwrap();
+      assert_eq!(new.range, old.range);
+      assert_eq!(new.algorithm.name(), old.algorithm.name());
+      assert_eq!(new.visible, old.visible);
+      let remembered = |fov: &Fov| {
+        let mut tiles = fov.memory.as_ref().unwrap().iter().collect::<Vec<_>>();
+        tiles.sort_by_key(|&(p, _)| (p.y(), p.x()));
+        tiles
+      };
+      assert_eq!(remembered(new), remembered(old));
+    }
+
+    // Go back up to find the monster right where it was left, still chasing
+    // the player.
+    assert_eq!(loaded_resources.get::<Dungeon>().unwrap().depth(), 1);
+    travel(&mut loaded, &mut loaded_resources, Stairs::Up);
+    assert_eq!(loaded_resources.get::<Dungeon>().unwrap().depth(), 0);
+
+    let floor = loaded_resources.get::<Floor>().unwrap();
+    let mut query = <(&Position, &Pathfind)>::query();
+    let (_, pf) = query
+      .iter(&loaded)
+      .find(|(pos, _)| pos.0 == start)
+      .expect("monster went missing");
+    assert_eq!(pf.script.len(), 2);
+    assert_eq!(pf.script[0].name(), "chase");
+    assert_eq!(pf.script[0].target(), Some(new_player));
+    assert_eq!(pf.script[1].name(), "wander");
+    assert_eq!(pf.goal, Some(path[0]));
+    assert_eq!(pf.path, path);
+    // The floor's revisions start over when it is loaded, so the path must
+    // still count as up to date against them.
+    assert!(!pf.path.iter().any(|&p| floor.changed_since(pf.revision, p)));
+  }
+}
@@ -1,8 +1,14 @@
 //! Basic actor components.
 
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
 use crate::geo::Point;
 use crate::geo::Dir;
 use crate::gfx::texel::Texel;
+use crate::save::Decode;
+use crate::save::Encode;
 
 /// Component: If this entity has a [`Position`], the renderer camera will focus
 /// on it.
@@ -18,4 +24,77 @@ pub struct Oriented(pub Dir);
 pub struct Tangible;
 
 /// Component: An actor with a sprite.
-pub struct Sprite(pub Texel);
\ No newline at end of file
+pub struct Sprite(pub Texel);
+
+/// Component: An actor that can swim through deep water.
+pub struct Swimmer;
+
+/// Component: An actor that can dig through walls, such as a player with a
+/// pick or a tunneling monster.
+pub struct Digger;
+
+/// Component: An actor that knows where the traps on its floor are, and so
+/// never springs them.
+pub struct Trapwise;
+
+/// Component: The gold an actor is carrying.
+pub struct Gold(pub u32);
+
+/// Component: An actor that is slowed down by rough terrain.
+///
+/// This holds the number of turns the actor must wait before it can move
+/// again; see [`TileDef::delay()`].
+///
+/// [`TileDef::delay()`]: crate::map::tile::TileDef::delay
+pub struct MoveDelay(pub u32);
+
+/// Component: An actor that can be hurt.
+pub struct Health {
+  /// The actor's current health; the actor dies when this reaches zero.
+  pub current: u32,
+  /// The actor's health when unhurt.
+  pub max: u32,
+}
+
+impl Health {
+  /// Creates a new, unhurt `Health`.
+  pub fn new(max: u32) -> Self {
+    Self { current: max, max }
+  }
+}
+
+impl Encode for Health {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.current.encode(w)?;
+    self.max.encode(w)
+  }
+}
+
+impl Decode for Health {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    Ok(Self {
+      current: Decode::decode(r)?,
+      max: Decode::decode(r)?,
+    })
+  }
+}
+
+macro_rules! newtype_save_impls {
+  ($($ty:ident,)*) => {$(
+    impl Encode for $ty {
+      fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.0.encode(w)
+      }
+    }
+
+    impl Decode for $ty {
+      fn decode(r: &mut dyn Read) -> io::Result<Self> {
+        Ok(Self(Decode::decode(r)?))
+      }
+    }
+  )*}
+}
+
+newtype_save_impls! {
+  Position, Oriented, Sprite, MoveDelay, Gold,
+}
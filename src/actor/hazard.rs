@@ -0,0 +1,79 @@
+//! Terrain hazards.
+
+use std::mem;
+
+use legion::systems::CommandBuffer;
+use legion::Entity;
+
+use crate::actor::ai::TurnMode;
+use crate::actor::base::Health;
+use crate::actor::base::Position;
+use crate::actor::base::Trapwise;
+use crate::actor::player::Player;
+use crate::geo::Point;
+use crate::map::Floor;
+use crate::map::Tiles;
+use crate::timing::SystemTimer;
+
+/// Component: Where an actor was standing the last time [`hazards()`] ran.
+///
+/// This is only bookkeeping for traps, so it is never saved; an actor that
+/// loses it simply can't spring a trap for a turn.
+pub struct LastPosition(pub Point);
+
+/// System: Hurts every actor standing on a hazardous tile, such as lava, at
+/// the end of each turn.
+///
+/// Traps are different: they only spring on the turn an actor steps onto them,
+/// revealing themselves if they were hidden, and never spring for actors that
+/// are [`Trapwise`]. Each actor's [`LastPosition`] is used to tell when they
+/// have moved; actors that don't have one yet are given one, and count as not
+/// having moved.
+///
+/// Actors other than players are removed when they die; what happens to a
+/// player is up to the caller, who can look for a player whose [`Health`] has
+/// run out.
+#[legion::system(for_each)]
+#[read_component(Position)]
+#[read_component(Player)]
+#[read_component(Trapwise)]
+#[write_component(Health)]
+#[write_component(LastPosition)]
+pub fn hazards(
+  entity: &Entity,
+  pos: &Position,
+  health: &mut Health,
+  last: Option<&mut LastPosition>,
+  player: Option<&Player>,
+  trapwise: Option<&Trapwise>,
+  commands: &mut CommandBuffer,
+  #[resource] floor: &mut Floor,
+  #[resource] tiles: &Tiles,
+  #[resource] mode: &TurnMode,
+  #[resource] timer: &SystemTimer,
+) {
+  let _t = timer.start("actor::hazard::hazards()");
+  if *mode != TurnMode::Running {
+    return;
+  }
+
+  let moved = match last {
+    Some(LastPosition(last)) => mem::replace(last, pos.0) != pos.0,
+    None => {
+      commands.add_component(*entity, LastPosition(pos.0));
+      false
+    }
+  };
+  let def = tiles.get(floor.tile(pos.0));
+  if def.is_trap() {
+    if !moved || trapwise.is_some() {
+      return;
+    }
+    floor.reveal(tiles, pos.0);
+  }
+
+  health.current = health.current.saturating_sub(def.damage);
+  if health.current == 0 && player.is_none() {
+    commands.remove(*entity);
+  }
+}
@@ -0,0 +1,465 @@
+//! Light sources and illumination.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use legion::query::IntoQuery;
+use legion::world::SubWorld;
+use legion::Entity;
+
+use crate::actor::base::Position;
+use crate::geo::fov;
+use crate::geo::Point;
+use crate::geo::PointSet;
+use crate::geo::Rect;
+use crate::gfx::texel::Rgb;
+use crate::map;
+use crate::map::Floor;
+use crate::map::Tiles;
+use crate::save::Decode;
+use crate::save::Encode;
+use crate::timing::SystemTimer;
+
+/// How bright a light is at the very edge of its range, relative to how bright
+/// it is at its source.
+const EDGE_BRIGHTNESS: f64 = 0.25;
+
+/// The width and height of the regions a [`LightMap`] tracks changes in, which
+/// matches the size of a map chunk.
+const REGION_WIDTH: i64 = map::WIDTH as i64;
+
+/// Returns the upper-left corner of the region containing `p`.
+fn region(p: Point<i64>) -> Point<i64> {
+  let [x, y] = p.coords();
+  Point::new(x.div_euclid(REGION_WIDTH), y.div_euclid(REGION_WIDTH))
+    * REGION_WIDTH
+}
+
+/// Component: Something that gives off light, such as a torch, a glowing
+/// monster, or the lamps of a lit room.
+pub struct Light {
+  /// The radius of the lit area, which is shaped like a [`Fov`]'s range.
+  ///
+  /// [`Fov`]: crate::actor::ai::Fov
+  pub range: Point<i64>,
+  /// The color of the light at its source.
+  pub color: Rgb,
+}
+
+impl Encode for Light {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.range.encode(w)?;
+    w.write_all(&[self.color.red, self.color.green, self.color.blue])
+  }
+}
+
+impl Decode for Light {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let range = Decode::decode(r)?;
+    let mut rgb = [0; 3];
+    r.read_exact(&mut rgb)?;
+    Ok(Self {
+      range,
+      color: Rgb::new(rgb[0], rgb[1], rgb[2]),
+    })
+  }
+}
+
+/// Resource: How brightly lit each tile of the current floor is.
+///
+/// This is kept up to date by [`update_lights()`], which only recomputes the
+/// lights that have changed since the last frame, so it is never saved. It
+/// must be [reset](LightMap::reset) whenever the current floor is replaced.
+///
+/// Like a [`Floor`], the map is split up into chunk-sized regions, each of
+/// which records the revision at which one of its tiles last became lit or
+/// went dark; see [`LightMap::changes_within()`].
+pub struct LightMap {
+  /// The total light falling on each lit tile, and how many lights it comes
+  /// from.
+  levels: HashMap<Point<i64>, ([f64; 3], u32)>,
+  sources: HashMap<Entity, Source>,
+  /// The revision at which each region last changed.
+  regions: HashMap<Point<i64>, u64>,
+  revision: u64,
+}
+
+/// What a single [`Light`] was contributing to a [`LightMap`] when it was last
+/// computed.
+struct Source {
+  pos: Point<i64>,
+  range: Point<i64>,
+  color: Rgb,
+  /// The floor revision the light was computed at.
+  revision: u64,
+  contributions: Vec<(Point<i64>, [f64; 3])>,
+}
+
+impl LightMap {
+  /// Creates a new, completely dark `LightMap`.
+  pub fn new() -> Self {
+    Self {
+      levels: HashMap::new(),
+      sources: HashMap::new(),
+      regions: HashMap::new(),
+      revision: 0,
+    }
+  }
+
+  /// Returns this map's current revision, which increases every time any tile
+  /// becomes lit or goes dark.
+  ///
+  /// Only whether a tile is lit at all is tracked this way: changes to the
+  /// color of the light falling on an already-lit tile don't count.
+  pub fn revision(&self) -> u64 {
+    self.revision
+  }
+
+  /// Returns the parts of `rect`, one for each region it overlaps, in which
+  /// some tile has become lit or gone dark since `revision`.
+  ///
+  /// Anything that depends on which tiles are lit, such as a field of view,
+  /// should record the revision it was computed at, and then only recheck the
+  /// tiles in these parts.
+  pub fn changes_within(
+    &self,
+    revision: u64,
+    rect: Rect,
+  ) -> impl Iterator<Item = Rect> + '_ {
+    rect
+      .disect(Rect::with_dims(REGION_WIDTH, REGION_WIDTH))
+      .filter(move |r| {
+        let changed = self.regions.get(&region(r.upper_left()));
+        matches!(changed, Some(&c) if c > revision)
+      })
+  }
+
+  /// Forgets every light, making the map completely dark.
+  ///
+  /// Unlike creating a new `LightMap`, this keeps the revision increasing, so
+  /// that anything computed from the old lighting is known to be out of date.
+  pub fn reset(&mut self) {
+    let lit = self
+      .levels
+      .keys()
+      .map(|&p| region(p))
+      .collect::<HashSet<_>>();
+    for r in lit {
+      self.mark_changed(r);
+    }
+    self.levels.clear();
+    self.sources.clear();
+  }
+
+  /// Returns whether any light at all falls on `p`.
+  pub fn is_lit(&self, p: Point<i64>) -> bool {
+    self.levels.contains_key(&p)
+  }
+
+  /// Returns the color of the light falling on `p`, or `None` if it is dark.
+  ///
+  /// Overlapping lights add up, saturating at white.
+  pub fn get(&self, p: Point<i64>) -> Option<Rgb> {
+    let (level, _) = self.levels.get(&p)?;
+    let channel = |x: f64| (x.min(1.0) * 255.0).round() as u8;
+    Some(Rgb::new(
+      channel(level[0]),
+      channel(level[1]),
+      channel(level[2]),
+    ))
+  }
+
+  /// Records that a tile in the region containing `p` became lit or went
+  /// dark.
+  fn mark_changed(&mut self, p: Point<i64>) {
+    self.revision += 1;
+    self.regions.insert(region(p), self.revision);
+  }
+
+  /// Adds a light's contributions to the map.
+  fn add(&mut self, contributions: &[(Point<i64>, [f64; 3])]) {
+    for (p, light) in contributions {
+      let (level, count) = self.levels.entry(*p).or_insert(([0.0; 3], 0));
+      for (l, c) in level.iter_mut().zip(light.iter()) {
+        *l += c;
+      }
+      *count += 1;
+      if *count == 1 {
+        self.mark_changed(*p);
+      }
+    }
+  }
+
+  /// Takes back contributions previously passed to [`LightMap::add()`].
+  fn subtract(&mut self, contributions: &[(Point<i64>, [f64; 3])]) {
+    for (p, light) in contributions {
+      let (level, count) = match self.levels.get_mut(p) {
+        Some(entry) => entry,
+        None => continue,
+      };
+      *count -= 1;
+      if *count == 0 {
+        self.levels.remove(p);
+        self.mark_changed(*p);
+        continue;
+      }
+      for (l, c) in level.iter_mut().zip(light.iter()) {
+        *l -= c;
+      }
+    }
+  }
+
+  /// Removes the light that `entity` gives off, if any.
+  fn remove_source(&mut self, entity: Entity) {
+    if let Some(source) = self.sources.remove(&entity) {
+      self.subtract(&source.contributions);
+    }
+  }
+}
+
+/// System: Updates the [`LightMap`] from every [`Light`].
+///
+/// Light spreads out from its source the same way sight does, so it lights up
+/// walls but doesn't pass through them, and it fades with distance down to
+/// [`EDGE_BRIGHTNESS`] at the edge of its range.
+///
+/// A light is only recomputed if it has moved or changed, or if the floor
+/// within its range has changed; lights that have gone away are removed.
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Light)]
+pub fn update_lights(
+  world: &SubWorld,
+  #[resource] light_map: &mut LightMap,
+  #[resource] floor: &Floor,
+  #[resource] tiles: &Tiles,
+  #[resource] timer: &SystemTimer,
+) {
+  let _t = timer.start("actor::light::update_lights()");
+
+  let mut seen = HashSet::new();
+  let mut lit = PointSet::new();
+  let mut q = <(Entity, &Position, &Light)>::query();
+  for (&entity, &Position(pos), light) in q.iter(world) {
+    seen.insert(entity);
+    let bounds =
+      Rect::new(pos - light.range, pos + light.range + Point::new(1, 1));
+    let is_current = |source: &Source| {
+      source.pos == pos
+        && source.range == light.range
+        && source.color == light.color
+        && !floor.changed_within(source.revision, bounds)
+    };
+    if matches!(light_map.sources.get(&entity), Some(s) if is_current(s)) {
+      continue;
+    }
+    let old = light_map.sources.remove(&entity);
+
+    // The octants of the FOV overlap along their edges, so points may be
+    // visited more than once; make sure they only get lit once.
+    lit.clear();
+    fov::milazzo(
+      pos,
+      light.range,
+      &mut |p| tiles.get(floor.tile(p)).is_opaque(),
+      &mut |p| {
+        lit.insert(p);
+      },
+    );
+
+    let [rx, ry] = light.range.coords();
+    let channels = [light.color.red, light.color.green, light.color.blue];
+    let contributions = lit
+      .iter()
+      .map(|p| {
+        let d = p - pos;
+        let norm = (d.x() as f64 / rx as f64).powi(2)
+          + (d.y() as f64 / ry as f64).powi(2);
+        let brightness = 1.0 - (1.0 - EDGE_BRIGHTNESS) * norm.sqrt().min(1.0);
+        let mut level = [0.0; 3];
+        for (l, &c) in level.iter_mut().zip(channels.iter()) {
+          *l = c as f64 / 255.0 * brightness;
+        }
+        (p, level)
+      })
+      .collect::<Vec<_>>();
+
+    // Adding the new light before taking away the old one means that tiles lit
+    // by both never go dark in between, so they don't count as changed.
+    light_map.add(&contributions);
+    if let Some(old) = old {
+      light_map.subtract(&old.contributions);
+    }
+    light_map.sources.insert(
+      entity,
+      Source {
+        pos,
+        range: light.range,
+        color: light.color,
+        revision: floor.revision(),
+        contributions,
+      },
+    );
+  }
+
+  let gone = light_map
+    .sources
+    .keys()
+    .filter(|e| !seen.contains(e))
+    .copied()
+    .collect::<Vec<_>>();
+  for entity in gone {
+    light_map.remove_source(entity);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use legion::EntityStore as _;
+  use legion::Resources;
+  use legion::Schedule;
+  use legion::World;
+
+  use crate::actor::ai::update_fov_system;
+  use crate::actor::ai::Fov;
+  use crate::gfx::texel::colors;
+
+  /// A big, open room, with a lamp in one corner, a light that can be moved
+  /// around in another, and two actors looking out over the whole thing from
+  /// the middle. The second actor's line of sight is recomputed from scratch
+  /// every time, to compare the first one's against.
+  struct Scene {
+    world: World,
+    resources: Resources,
+    schedule: Schedule,
+    lamp: Entity,
+    glow: Entity,
+    viewer: Entity,
+    fresh_viewer: Entity,
+  }
+
+  impl Scene {
+    fn new() -> Self {
+      let mut floor = Floor::new();
+      floor.add_room(Rect::new(Point::new(-40, -40), Point::new(40, 40)));
+
+      let mut world = World::default();
+      let light = |range| Light {
+        range,
+        color: colors::WHITE,
+      };
+      let lamp =
+        world.push((Position(Point::new(-30, -10)), light(Point::new(6, 3))));
+      let glow =
+        world.push((Position(Point::new(30, 10)), light(Point::new(4, 2))));
+      let viewer = world.push((
+        Position(Point::zero()),
+        Fov::new(Point::new(38, 38), &fov::Milazzo).with_memory(),
+      ));
+      let fresh_viewer = world.push((
+        Position(Point::zero()),
+        Fov::new(Point::new(38, 38), &fov::Milazzo),
+      ));
+
+      let mut resources = Resources::default();
+      resources.insert(floor);
+      resources.insert(Tiles::builtin());
+      resources.insert(LightMap::new());
+      resources.insert(SystemTimer::new());
+      let schedule = Schedule::builder()
+        .add_system(update_lights_system())
+        .add_system(update_fov_system())
+        .build();
+
+      let mut scene = Self {
+        world,
+        resources,
+        schedule,
+        lamp,
+        glow,
+        viewer,
+        fresh_viewer,
+      };
+      scene.step();
+      scene
+    }
+
+    fn step(&mut self) {
+      let mut entry = self.world.entry(self.fresh_viewer).unwrap();
+      entry.get_component_mut::<Fov>().unwrap().reset();
+      self.schedule.execute(&mut self.world, &mut self.resources);
+
+      let visible = |e| {
+        let entry = self.world.entry_ref(e).unwrap();
+        entry.get_component::<Fov>().unwrap().visible.clone()
+      };
+      assert_eq!(visible(self.viewer), visible(self.fresh_viewer));
+    }
+
+    fn revision(&self) -> u64 {
+      self.resources.get::<LightMap>().unwrap().revision()
+    }
+
+    fn changes_since(&self, revision: u64) -> Vec<Rect> {
+      let everything = Rect::new(Point::new(-50, -50), Point::new(50, 50));
+      let lights = self.resources.get::<LightMap>().unwrap();
+      lights.changes_within(revision, everything).collect()
+    }
+
+    fn move_light(&mut self, e: Entity, by: Point<i64>) {
+      let mut entry = self.world.entry(e).unwrap();
+      entry.get_component_mut::<Position>().unwrap().0 += by;
+    }
+  }
+
+  #[test]
+  fn moving_a_light_only_changes_its_regions() {
+    let mut scene = Scene::new();
+    let start = scene.revision();
+    {
+      let lights = scene.resources.get::<LightMap>().unwrap();
+      assert!(lights.is_lit(Point::new(-30, -10)));
+      assert!(lights.is_lit(Point::new(30, 10)));
+      assert!(!lights.is_lit(Point::zero()));
+    }
+
+    // Nothing moving means nothing changing.
+    scene.step();
+    assert_eq!(scene.revision(), start);
+    assert!(scene.changes_since(start).is_empty());
+
+    // Nor does changing a light's color, since the same tiles are still lit.
+    let mut entry = scene.world.entry(scene.lamp).unwrap();
+    entry.get_component_mut::<Light>().unwrap().color = colors::RED;
+    scene.step();
+    assert_eq!(scene.revision(), start);
+
+    // Moving the glowing light only changes the regions it lights up.
+    scene.move_light(scene.glow, Point::new(1, 0));
+    scene.step();
+    let changes = scene.changes_since(start);
+    assert!(!changes.is_empty());
+    let around_glow = Rect::new(Point::new(25, 7), Point::new(36, 13));
+    for rect in changes {
+      assert!(rect.intersect(around_glow).is_some(), "{:?}", rect);
+    }
+
+    // Taking lights away and putting them back changes things too.
+    for _ in 0..3 {
+      scene.move_light(scene.glow, Point::new(-3, 1));
+      scene.step();
+    }
+    scene.world.remove(scene.lamp);
+    scene.step();
+    assert!(!scene
+      .resources
+      .get::<LightMap>()
+      .unwrap()
+      .is_lit(Point::new(-30, -10)));
+  }
+}
@@ -2,4 +2,6 @@
 
 pub mod ai;
 pub mod base;
+pub mod hazard;
+pub mod light;
 pub mod player;
\ No newline at end of file
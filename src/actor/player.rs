@@ -1,29 +1,53 @@
 //! Player-specific components and systems.
 
 use legion::query::component;
+use rand::Rng as _;
 
 use crate::actor::ai::TurnMode;
 use crate::geo::Dir;
+use crate::geo::Rect;
 use crate::input::KeyCode;
 use crate::input::KeyModifiers;
 use crate::input::UserInput;
+use crate::map::dungeon::Stairs;
+use crate::map::tile::Mobility;
+use crate::map::Dungeon;
 use crate::map::Floor;
 use crate::map::Tile;
+use crate::map::Tiles;
+use crate::rng::Rng;
 use crate::timing::SystemTimer;
+use crate::actor::base::Digger;
+use crate::actor::base::Gold;
+use crate::actor::base::MoveDelay;
 use crate::actor::base::Position;
 use crate::actor::base::Oriented;
+use crate::actor::base::Swimmer;
 
 /// Component: A "player" actor.
 pub struct Player;
 
+/// System: Moves the player according to the keys that were pressed.
+///
+/// The player moves by the same rules as everyone else: they can only swim if
+/// they are a [`Swimmer`], and rough terrain makes them spend extra turns
+/// wading, per their [`MoveDelay`]. A player who is a [`Digger`] can also dig
+/// through whatever they are facing.
 #[legion::system(for_each)]
 #[write_component(Position)]
 #[write_component(Oriented)]
+#[read_component(Swimmer)]
+#[read_component(Digger)]
+#[write_component(MoveDelay)]
 #[filter(component::<Player>())]
 pub fn player_movement(
   pos: &mut Position,
   dir: &mut Oriented,
-  #[resource] floor: &Floor,
+  swimmer: Option<&Swimmer>,
+  digger: Option<&Digger>,
+  mut delay: Option<&mut MoveDelay>,
+  #[resource] floor: &mut Floor,
+  #[resource] tiles: &Tiles,
   #[resource] input: &UserInput,
   #[resource] timer: &SystemTimer,
   #[resource] turn_mode: &mut TurnMode,
@@ -57,12 +81,33 @@ pub fn player_movement(
     if input.has_key(KeyCode::Char(dir_char(d))) {
       dir.0 = d;
       if !shifted {
+        if let Some(MoveDelay(turns)) = delay.as_deref_mut() {
+          if *turns > 0 {
+            // Still wading; this turn is spent getting free.
+            *turns -= 1;
+            *turn_mode = TurnMode::Running;
+            return;
+          }
+        }
+
         let new_pos = pos.0 + d.to_point::<i64>();
-        match floor.chunk(new_pos).unwrap().tile(new_pos) {
-          Tile::Wall | Tile::Void => continue,
-          _ => {}
+        if floor.open(tiles, new_pos) {
+          // Opening a door takes up the whole turn.
+          *turn_mode = TurnMode::Running;
+          return;
+        }
+        let def = tiles.get(floor.tile(new_pos));
+        let mobility = Mobility {
+          swims: swimmer.is_some(),
+          digs: digger.is_some(),
         };
+        if !def.can_enter(mobility) {
+          continue;
+        }
         pos.0 = new_pos;
+        if let Some(MoveDelay(turns)) = delay {
+          *turns = def.delay();
+        }
         *turn_mode = TurnMode::Running;
       }
       // Only select *one* key per step.
@@ -72,6 +117,109 @@ pub fn player_movement(
 
   // Wait is x.
   if !shifted && input.has_key(KeyCode::Char('x')) {
+    if let Some(MoveDelay(turns)) = delay {
+      *turns = turns.saturating_sub(1);
+    }
+    *turn_mode = TurnMode::Running;
+    return;
+  }
+
+  // Dig is g, which digs out whatever the player is facing.
+  if !shifted
+    && digger.is_some()
+    && input.has_key(KeyCode::Char('g'))
+    && floor.dig(tiles, pos.0 + dir.0.to_point::<i64>())
+  {
     *turn_mode = TurnMode::Running;
   }
-}
\ No newline at end of file
+}
+
+/// How far away from the player a search can turn up hidden tiles.
+const SEARCH_RADIUS: i64 = 2;
+
+/// The chance that a search turns up each hidden tile in range.
+const SEARCH_CHANCE: f64 = 0.4;
+
+/// System: Searches around the player for hidden tiles, such as traps and
+/// secret doors, when they press the search key.
+///
+/// Searching takes up the whole turn, and only has a chance of turning up
+/// any given tile, so it may take a few tries.
+#[legion::system(for_each)]
+#[read_component(Position)]
+#[write_component(MoveDelay)]
+#[filter(component::<Player>())]
+pub fn search(
+  pos: &Position,
+  delay: Option<&mut MoveDelay>,
+  #[resource] floor: &mut Floor,
+  #[resource] tiles: &Tiles,
+  #[resource] input: &UserInput,
+  #[resource] rng: &mut Rng,
+  #[resource] timer: &SystemTimer,
+  #[resource] turn_mode: &mut TurnMode,
+) {
+  let _t = timer.start("actor::player::search()");
+
+  // Search is f.
+  if input.has_mod(KeyModifiers::SHIFT) || !input.has_key(KeyCode::Char('f')) {
+    return;
+  }
+
+  if let Some(MoveDelay(turns)) = delay {
+    if *turns > 0 {
+      // Still wading; this turn is spent getting free.
+      *turns -= 1;
+      *turn_mode = TurnMode::Running;
+      return;
+    }
+  }
+
+  let rng = rng.stream("search");
+  let area = Rect::with_dims(SEARCH_RADIUS * 2 + 1, SEARCH_RADIUS * 2 + 1)
+    .centered_on(pos.0);
+  for p in area.points() {
+    if tiles.get(floor.tile(p)).is_hidden() && rng.gen_bool(SEARCH_CHANCE) {
+      floor.reveal(tiles, p);
+    }
+  }
+  *turn_mode = TurnMode::Running;
+}
+
+/// System: Picks up any gold the player is standing on.
+#[legion::system(for_each)]
+#[read_component(Position)]
+#[write_component(Gold)]
+#[filter(component::<Player>())]
+pub fn pick_up_gold(
+  pos: &Position,
+  gold: &mut Gold,
+  #[resource] floor: &mut Floor,
+  #[resource] tiles: &Tiles,
+) {
+  let amount = tiles.get(floor.tile(pos.0)).gold;
+  if amount > 0 {
+    gold.0 += amount;
+    *floor.chunk_mut(pos.0).tile_mut(pos.0) = Tile::Ground;
+  }
+}
+
+#[legion::system(for_each)]
+#[read_component(Position)]
+#[filter(component::<Player>())]
+pub fn take_stairs(
+  pos: &Position,
+  #[resource] floor: &Floor,
+  #[resource] input: &UserInput,
+  #[resource] dungeon: &mut Dungeon,
+) {
+  let (stairs, key) = match floor.tile(pos.0) {
+    Tile::StairsDown => (Stairs::Down, '>'),
+    Tile::StairsUp => (Stairs::Up, '<'),
+    _ => return,
+  };
+
+  if input.has_key(KeyCode::Char(key)) {
+    dungeon.take_stairs(stairs);
+  }
+}
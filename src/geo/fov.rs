@@ -446,3 +446,712 @@ pub fn milazzo(
     }
   }
 }
+
+/// A field-of-view algorithm.
+///
+/// Algorithms differ in which tiles they consider visible around corners and
+/// through gaps between obstructions; all of them treat the range the same way
+/// [`milazzo()`] does.
+pub trait Algorithm: Send + Sync {
+  /// The name this algorithm is saved under; see [`by_name()`].
+  fn name(&self) -> &'static str;
+
+  /// Computes the field-of-view from `origin`, calling `ignite` on every
+  /// visible point; see [`milazzo()`] for the meaning of the arguments.
+  fn compute(
+    &self,
+    origin: Point<i64>,
+    range: Point<i64>,
+    is_opaque: &mut dyn FnMut(Point<i64>) -> bool,
+    ignite: &mut dyn FnMut(Point<i64>),
+  );
+
+  /// Like [`Algorithm::compute()`], but only calls `ignite` on points that
+  /// lie within `cone` of `origin`.
+  fn compute_in_cone(
+    &self,
+    origin: Point<i64>,
+    range: Point<i64>,
+    cone: Cone,
+    is_opaque: &mut dyn FnMut(Point<i64>) -> bool,
+    ignite: &mut dyn FnMut(Point<i64>),
+  ) {
+    self.compute(origin, range, is_opaque, &mut |p| {
+      if cone.contains(p - origin) {
+        ignite(p)
+      }
+    })
+  }
+}
+
+/// An angular restriction on a field-of-view, such as the cone of vision in
+/// front of someone's eyes.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Cone {
+  /// The direction the cone points in, which must not be zero, but need not be
+  /// a unit vector.
+  pub facing: Point<i64>,
+  /// How far to either side of `facing` the cone extends, in radians.
+  pub half_width: f64,
+  /// The radius of the area around the origin that is in view regardless of
+  /// direction, which is shaped like the FOV range.
+  pub peripheral: Point<i64>,
+}
+
+impl Cone {
+  /// Returns whether the offset `d`, relative to the cone's origin, lies
+  /// within it.
+  pub fn contains(self, d: Point<i64>) -> bool {
+    if d == Point::zero() || in_range(d, self.peripheral) {
+      return true;
+    }
+
+    let len = |p: Point<i64>| ((p.x() * p.x() + p.y() * p.y()) as f64).sqrt();
+    let cos = d.dot(self.facing) as f64 / (len(d) * len(self.facing));
+    cos >= self.half_width.cos()
+  }
+}
+
+/// Looks up an [`Algorithm`] by its name.
+pub fn by_name(name: &str) -> Option<&'static dyn Algorithm> {
+  match name {
+    "milazzo" => Some(&Milazzo),
+    "shadowcast" => Some(&Shadowcast),
+    "permissive" => Some(&Permissive),
+    _ => None,
+  }
+}
+
+/// Milazzo's algorithm; see [`milazzo()`].
+pub struct Milazzo;
+
+impl Algorithm for Milazzo {
+  fn name(&self) -> &'static str {
+    "milazzo"
+  }
+
+  fn compute(
+    &self,
+    origin: Point<i64>,
+    range: Point<i64>,
+    is_opaque: &mut dyn FnMut(Point<i64>) -> bool,
+    ignite: &mut dyn FnMut(Point<i64>),
+  ) {
+    milazzo(origin, range, is_opaque, ignite)
+  }
+}
+
+/// Symmetric shadowcasting; see [`shadowcast()`].
+pub struct Shadowcast;
+
+impl Algorithm for Shadowcast {
+  fn name(&self) -> &'static str {
+    "shadowcast"
+  }
+
+  fn compute(
+    &self,
+    origin: Point<i64>,
+    range: Point<i64>,
+    is_opaque: &mut dyn FnMut(Point<i64>) -> bool,
+    ignite: &mut dyn FnMut(Point<i64>),
+  ) {
+    shadowcast(origin, range, is_opaque, ignite)
+  }
+}
+
+/// Permissive shadowcasting; see [`permissive()`].
+pub struct Permissive;
+
+impl Algorithm for Permissive {
+  fn name(&self) -> &'static str {
+    "permissive"
+  }
+
+  fn compute(
+    &self,
+    origin: Point<i64>,
+    range: Point<i64>,
+    is_opaque: &mut dyn FnMut(Point<i64>) -> bool,
+    ignite: &mut dyn FnMut(Point<i64>),
+  ) {
+    permissive(origin, range, is_opaque, ignite)
+  }
+}
+
+/// A slope, represented as the rational number `num / den`, where `den` is
+/// always positive.
+#[derive(Copy, Clone, Debug)]
+struct Slope {
+  num: i64,
+  den: i64,
+}
+
+impl Slope {
+  fn new(num: i64, den: i64) -> Self {
+    Self { num, den }
+  }
+
+  /// Returns `self * n`, rounded to the nearest integer, with ties rounded
+  /// up.
+  fn round_ties_up(self, n: i64) -> i64 {
+    (2 * self.num * n + self.den).div_euclid(2 * self.den)
+  }
+
+  /// Returns `self * n`, rounded to the nearest integer, with ties rounded
+  /// down.
+  fn round_ties_down(self, n: i64) -> i64 {
+    -(self.den - 2 * self.num * n).div_euclid(2 * self.den)
+  }
+}
+
+impl PartialEq for Slope {
+  fn eq(&self, other: &Self) -> bool {
+    self.num * other.den == other.num * self.den
+  }
+}
+
+impl PartialOrd for Slope {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    (self.num * other.den).partial_cmp(&(other.num * self.den))
+  }
+}
+
+/// Returns whether the offset `d` lies within the ellipse with semi-axes
+/// `range`, the same way [`milazzo()`] does.
+fn in_range(d: Point<i64>, range: Point<i64>) -> bool {
+  let [a, b] = range.coords();
+  d.x() * d.x() * b * b + d.y() * d.y() * a * a < a * a * b * b
+}
+
+/// Compute the field-of-view from a given point using symmetric shadowcasting.
+///
+/// This takes the same arguments as [`milazzo()`]. Unlike Milazzo's algorithm,
+/// floor tiles are visible only if their centers can be seen from the center
+/// of the origin, which makes the result symmetric: if `a` can see `b`, then
+/// `b` can see `a`. Opaque tiles are visible if any part of them can be seen,
+/// so that walls are lit up.
+///
+/// Lines of sight are only checked against the middle of each wall, so they
+/// may clip the corners of walls, and pass through a diagonal gap between two
+/// walls as long as they go exactly through the corner.
+///
+/// See https://www.albertford.com/shadowcasting/
+pub fn shadowcast(
+  origin: Point<i64>,
+  range: Point<i64>,
+  is_opaque: &mut dyn FnMut(Point<i64>) -> bool,
+  ignite: &mut dyn FnMut(Point<i64>),
+) {
+  /// A row of tiles in a quadrant, lying `depth` tiles away from the origin,
+  /// which is only visible between `start` and `end`.
+  #[derive(Copy, Clone)]
+  struct Row {
+    depth: i64,
+    start: Slope,
+    end: Slope,
+  }
+
+  /// State for the algorithm that is not tracked by recursion frames.
+  struct State<'a> {
+    origin: Point<i64>,
+    range: Point<i64>,
+    is_opaque: &'a mut dyn FnMut(Point<i64>) -> bool,
+    ignite: &'a mut dyn FnMut(Point<i64>),
+    quadrant: u8,
+  }
+
+  impl State<'_> {
+    /// Transforms a (depth, column) pair in the current quadrant into map
+    /// coordinates. Quadrants 0 through 3 face north, east, south and west.
+    fn quad2map(&self, depth: i64, col: i64) -> Point<i64> {
+      let [ox, oy] = self.origin.coords();
+      match self.quadrant {
+        0 => Point::new(ox + col, oy - depth),
+        1 => Point::new(ox + depth, oy + col),
+        2 => Point::new(ox + col, oy + depth),
+        _ => Point::new(ox - depth, oy + col),
+      }
+    }
+
+    /// Returns how deep the current quadrant goes.
+    fn max_depth(&self) -> i64 {
+      match self.quadrant {
+        0 | 2 => self.range.y(),
+        _ => self.range.x(),
+      }
+    }
+
+    fn scan(&mut self, mut row: Row) {
+      if row.depth >= self.max_depth() {
+        return;
+      }
+
+      // Columns are visible when their centers lie between the start and end
+      // slopes; ties are rounded towards the middle of the row.
+      let min_col = row.start.round_ties_up(row.depth);
+      let max_col = row.end.round_ties_down(row.depth);
+
+      let mut was_opaque = None;
+      for col in min_col..=max_col {
+        let p = self.quad2map(row.depth, col);
+        let is_opaque = (self.is_opaque)(p);
+
+        // Floor tiles are only visible if their center is within the row's
+        // visible sector; walls are visible if any part of them is.
+        let center = Slope::new(col, row.depth);
+        let is_symmetric = center >= row.start && center <= row.end;
+        if (is_opaque || is_symmetric) && in_range(p - self.origin, self.range)
+        {
+          (self.ignite)(p);
+        }
+
+        // The slope of the left edge of this column.
+        let edge = Slope::new(2 * col - 1, 2 * row.depth);
+        match (was_opaque, is_opaque) {
+          (Some(true), false) => row.start = edge,
+          (Some(false), true) => self.scan(Row {
+            depth: row.depth + 1,
+            start: row.start,
+            end: edge,
+          }),
+          _ => {}
+        }
+        was_opaque = Some(is_opaque);
+      }
+
+      if was_opaque == Some(false) {
+        self.scan(Row {
+          depth: row.depth + 1,
+          ..row
+        });
+      }
+    }
+  }
+
+  ignite(origin);
+  #[rustfmt::skip]
+  let mut state = State { origin, range, is_opaque, ignite, quadrant: 0 };
+  for quadrant in 0..4 {
+    state.quadrant = quadrant;
+    state.scan(Row {
+      depth: 1,
+      start: Slope::new(-1, 1),
+      end: Slope::new(1, 1),
+    });
+  }
+}
+
+/// Compute the field-of-view from a given point using precise permissive FOV.
+///
+/// This takes the same arguments as [`milazzo()`]. Both the origin and every
+/// other tile are treated as whole squares: a tile is visible if any line from
+/// anywhere in the origin's square reaches anywhere in its square without
+/// passing through an opaque tile. This is symmetric, and usually sees
+/// further around corners than the other algorithms do, but it lets actors see
+/// through diagonal gaps between walls as though they weren't there.
+///
+/// See http://www.roguebasin.com/index.php/Precise_Permissive_Field_of_View
+pub fn permissive(
+  origin: Point<i64>,
+  range: Point<i64>,
+  is_opaque: &mut dyn FnMut(Point<i64>) -> bool,
+  ignite: &mut dyn FnMut(Point<i64>),
+) {
+  // Within a quadrant, tiles are unit squares whose bottom-left corners are
+  // their coordinates, so the origin occupies the square from (0, 0) to
+  // (1, 1). Lines run from the origin's square outwards.
+
+  /// A line from `from` to `to`.
+  #[derive(Copy, Clone)]
+  struct Line {
+    from: Point<i64>,
+    to: Point<i64>,
+  }
+
+  impl Line {
+    /// Returns a positive number if `p` is above (counterclockwise of) this
+    /// line, a negative one if it is below it, and zero if it is on it.
+    fn side(self, p: Point<i64>) -> i64 {
+      let d = self.to - self.from;
+      let q = p - self.from;
+      d.x() * q.y() - d.y() * q.x()
+    }
+
+    fn is_colinear(self, other: Line) -> bool {
+      self.side(other.from) == 0 && self.side(other.to) == 0
+    }
+  }
+
+  /// A wedge of the quadrant that can still be seen, between a shallow line
+  /// and a steep line. Bumps are the corners of opaque tiles that the lines
+  /// have been bent around.
+  #[derive(Clone)]
+  struct View {
+    shallow: Line,
+    steep: Line,
+    shallow_bumps: Vec<Point<i64>>,
+    steep_bumps: Vec<Point<i64>>,
+  }
+
+  impl View {
+    /// Raises the shallow line so that it passes above `p`.
+    fn add_shallow_bump(&mut self, p: Point<i64>) {
+      self.shallow.to = p;
+      self.shallow_bumps.push(p);
+      for &b in self.steep_bumps.iter().rev() {
+        if self.shallow.side(b) < 0 {
+          self.shallow.from = b;
+        }
+      }
+    }
+
+    /// Lowers the steep line so that it passes below `p`.
+    fn add_steep_bump(&mut self, p: Point<i64>) {
+      self.steep.to = p;
+      self.steep_bumps.push(p);
+      for &b in self.shallow_bumps.iter().rev() {
+        if self.steep.side(b) > 0 {
+          self.steep.from = b;
+        }
+      }
+    }
+
+    /// Returns whether anything can still be seen through this view.
+    fn is_open(&self) -> bool {
+      !(self.shallow.is_colinear(self.steep)
+        && (self.shallow.side(Point::new(0, 1)) == 0
+          || self.shallow.side(Point::new(1, 0)) == 0))
+    }
+  }
+
+  ignite(origin);
+  for &(dx, dy) in &[(1, 1), (-1, 1), (-1, -1), (1, -1)] {
+    let [ex, ey] = range.coords();
+    let mut views = vec![View {
+      shallow: Line {
+        from: Point::new(0, 1),
+        to: Point::new(ex, 0),
+      },
+      steep: Line {
+        from: Point::new(1, 0),
+        to: Point::new(0, ey),
+      },
+      shallow_bumps: Vec::new(),
+      steep_bumps: Vec::new(),
+    }];
+
+    // Tiles are visited in order of their taxicab distance from the origin,
+    // and from the shallowest to the steepest.
+    for i in 1..=ex + ey {
+      if views.is_empty() {
+        break;
+      }
+
+      let mut current = 0;
+      for j in (i - ex).max(0)..=i.min(ey) {
+        let (x, y) = (i - j, j);
+        let top_left = Point::new(x, y + 1);
+        let bottom_right = Point::new(x + 1, y);
+
+        // Skip past views that this tile is entirely above.
+        while current < views.len()
+          && views[current].steep.side(bottom_right) >= 0
+        {
+          current += 1;
+        }
+        if current == views.len() {
+          break;
+        }
+        let view = &mut views[current];
+        if view.shallow.side(top_left) <= 0 {
+          continue;
+        }
+
+        let p = origin + Point::new(x * dx, y * dy);
+        if in_range(p - origin, range) {
+          ignite(p);
+        }
+        if !is_opaque(p) {
+          continue;
+        }
+
+        let cuts_shallow = view.shallow.side(bottom_right) < 0;
+        let cuts_steep = view.steep.side(top_left) > 0;
+        match (cuts_shallow, cuts_steep) {
+          (true, true) => {
+            views.remove(current);
+          }
+          (true, false) => {
+            view.add_shallow_bump(top_left);
+            if !view.is_open() {
+              views.remove(current);
+            }
+          }
+          (false, true) => {
+            view.add_steep_bump(bottom_right);
+            if !view.is_open() {
+              views.remove(current);
+            }
+          }
+          (false, false) => {
+            // The tile splits the view in two.
+            let mut shallower = view.clone();
+            view.add_shallow_bump(top_left);
+            let steeper_is_open = view.is_open();
+            shallower.add_steep_bump(bottom_right);
+            if !steeper_is_open {
+              views.remove(current);
+            }
+            if shallower.is_open() {
+              views.insert(current, shallower);
+            }
+          }
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::collections::HashMap;
+
+  use rand::Rng as _;
+
+  use crate::geo::PointSet;
+  use crate::geo::Rect;
+  use crate::rng;
+
+  /// A range large enough that it never matters in these tests.
+  const RANGE: i64 = 100;
+
+  /// A small map for testing FOV on; anything outside of it is opaque.
+  struct Map {
+    bounds: Rect,
+    walls: PointSet,
+    origin: Point<i64>,
+  }
+
+  impl Map {
+    /// Parses a map drawn with `#` for walls, `.` for floors and `@` for the
+    /// origin. Leading whitespace on each line is ignored.
+    fn parse(map: &str) -> Self {
+      let rows = map
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>();
+      let mut walls = PointSet::new();
+      let mut origin = None;
+      for (y, row) in rows.iter().enumerate() {
+        for (x, c) in row.chars().enumerate() {
+          let p = Point::new(x as i64, y as i64);
+          match c {
+            '#' => {
+              walls.insert(p);
+            }
+            '@' => origin = Some(p),
+            _ => {}
+          }
+        }
+      }
+
+      Map {
+        bounds: Rect::with_dims(rows[0].len() as i64, rows.len() as i64),
+        walls,
+        origin: origin.unwrap_or(Point::zero()),
+      }
+    }
+
+    /// Generates a random map, with each tile being a wall with probability
+    /// `density`.
+    fn random(seed: u64, dims: i64, density: f64) -> Self {
+      let mut rng = rng::Rng::new(seed);
+      let rng = rng.stream("fov");
+      let bounds = Rect::with_dims(dims, dims);
+      let walls = bounds.points().filter(|_| rng.gen_bool(density)).collect();
+      Map {
+        bounds,
+        walls,
+        origin: Point::zero(),
+      }
+    }
+
+    fn is_opaque(&self, p: Point<i64>) -> bool {
+      !self.bounds.contains(p) || self.walls.contains(p)
+    }
+
+    /// Returns the floor tiles of this map.
+    fn floors(&self) -> Vec<Point<i64>> {
+      self
+        .bounds
+        .points()
+        .filter(|&p| !self.is_opaque(p))
+        .collect()
+    }
+
+    /// Computes what is visible from `origin` with `algorithm`.
+    fn fov_from(
+      &self,
+      algorithm: &dyn Algorithm,
+      origin: Point<i64>,
+    ) -> PointSet {
+      let mut visible = PointSet::new();
+      let range = Point::new(RANGE, RANGE);
+      algorithm.compute(origin, range, &mut |p| self.is_opaque(p), &mut |p| {
+        visible.insert(p);
+      });
+      visible
+    }
+
+    /// Computes what is visible from this map's origin with `algorithm`.
+    fn fov(&self, algorithm: &dyn Algorithm) -> PointSet {
+      self.fov_from(algorithm, self.origin)
+    }
+  }
+
+  /// Checks that, between floor tiles, `algorithm` is symmetric on a handful
+  /// of random maps.
+  fn assert_symmetric(algorithm: &dyn Algorithm) {
+    for seed in 0..5 {
+      let map = Map::random(seed, 16, 0.3);
+      let floors = map.floors();
+      let fovs = floors
+        .iter()
+        .map(|&p| (p, map.fov_from(algorithm, p)))
+        .collect::<HashMap<_, _>>();
+      for (&a, fov) in &fovs {
+        for &b in &floors {
+          assert_eq!(
+            fov.contains(b),
+            fovs[&b].contains(a),
+            "{}: {:?} and {:?} disagree on seed {}",
+            algorithm.name(),
+            a,
+            b,
+            seed
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn shadowcast_is_symmetric() {
+    assert_symmetric(&Shadowcast);
+  }
+
+  #[test]
+  fn permissive_is_symmetric() {
+    assert_symmetric(&Permissive);
+  }
+
+  #[test]
+  fn open_room() {
+    let map = Map::parse(
+      "
+      .....
+      .....
+      ..@..
+      .....
+      .....
+      ",
+    );
+    for algorithm in &[&Milazzo as &dyn Algorithm, &Shadowcast, &Permissive] {
+      let fov = map.fov(*algorithm);
+      for p in map.bounds.points() {
+        assert!(fov.contains(p), "{}: {:?}", algorithm.name(), p);
+      }
+    }
+  }
+
+  #[test]
+  fn pillar() {
+    let map = Map::parse(
+      "
+      .........
+      .........
+      .@.#.....
+      .........
+      .........
+      ",
+    );
+    for algorithm in &[&Milazzo as &dyn Algorithm, &Shadowcast, &Permissive] {
+      let fov = map.fov(*algorithm);
+      let name = algorithm.name();
+      assert!(fov.contains(Point::new(3, 2)), "{}", name);
+      for x in 4..9 {
+        assert!(!fov.contains(Point::new(x, 2)), "{}: {}", name, x);
+      }
+      assert!(fov.contains(Point::new(8, 0)), "{}", name);
+      assert!(fov.contains(Point::new(8, 4)), "{}", name);
+    }
+  }
+
+  #[test]
+  fn diagonal_gap() {
+    let map = Map::parse(
+      "
+      @#.....
+      #......
+      .......
+      .......
+      ",
+    );
+    let shadowcast = map.fov(&Shadowcast);
+    let permissive = map.fov(&Permissive);
+
+    // Both can see along the diagonal itself...
+    for i in 1..3 {
+      assert!(shadowcast.contains(Point::new(i, i)), "{}", i);
+      assert!(permissive.contains(Point::new(i, i)), "{}", i);
+    }
+
+    // ...but only permissive FOV sees the whole area beyond the gap.
+    for &p in &[Point::new(4, 1), Point::new(6, 2), Point::new(1, 3)] {
+      assert!(!shadowcast.contains(p), "{:?}", p);
+      assert!(permissive.contains(p), "{:?}", p);
+    }
+  }
+
+  #[test]
+  fn corridor_mouth() {
+    let map = Map::parse(
+      "
+      .........
+      .........
+      .........
+      ####.####
+      ####.####
+      ####@####
+      ",
+    );
+    let shadowcast = map.fov(&Shadowcast);
+    let permissive = map.fov(&Permissive);
+
+    // Straight ahead is in view either way, as are the walls of the corridor.
+    for y in 0..5 {
+      assert!(shadowcast.contains(Point::new(4, y)), "{}", y);
+      assert!(permissive.contains(Point::new(4, y)), "{}", y);
+    }
+    for &p in &[Point::new(3, 4), Point::new(5, 4)] {
+      assert!(shadowcast.contains(p), "{:?}", p);
+      assert!(permissive.contains(p), "{:?}", p);
+    }
+
+    // Permissive FOV sees further into the corners of the room.
+    let count = |fov: &PointSet| {
+      Rect::with_dims(9, 3)
+        .points()
+        .filter(|&p| fov.contains(p))
+        .count()
+    };
+    assert!(count(&permissive) > count(&shadowcast));
+    for p in shadowcast.iter().filter(|&p| !map.is_opaque(p)) {
+      assert!(permissive.contains(p), "{:?}", p);
+    }
+  }
+}
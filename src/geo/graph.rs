@@ -1,4 +1,4 @@
-//! Graph algorithms, primarially for use by AI.
+//! Graph algorithms, primarially for use by AI and map generation.
 
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
@@ -6,6 +6,34 @@ use std::collections::HashMap;
 
 use crate::geo::Dir;
 use crate::geo::Point;
+use crate::geo::PointSet;
+use crate::geo::Rect;
+use crate::geo::RectVec;
+
+/// A point in a search frontier, ordered so that `BinaryHeap` pops the
+/// cheapest one first.
+#[derive(Copy, Clone)]
+struct Node(f64, Point);
+impl PartialEq for Node {
+  fn eq(&self, other: &Self) -> bool {
+    self.0 == other.0
+  }
+}
+impl Eq for Node {}
+impl PartialOrd for Node {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+impl Ord for Node {
+  fn cmp(&self, other: &Self) -> Ordering {
+    self
+      .0
+      .partial_cmp(&other.0)
+      .map(Ordering::reverse)
+      .unwrap_or(Ordering::Less)
+  }
+}
 
 /// Implements the A* pathfinding algorithm with Manhattan distance and
 /// heuristic functions.
@@ -14,12 +42,12 @@ use crate::geo::Point;
 pub fn manhattan_a_star(
   start: Point,
   goal: Point,
-  can_walk: impl FnMut(Point) -> bool,
+  mut can_walk: impl FnMut(Point) -> bool,
 ) -> Option<Vec<Point>> {
   a_star(
     start,
     goal,
-    can_walk,
+    |_, p| can_walk(p),
     |a, b| (a - b).manhattan() as f64,
     move |n| (n - goal).manhattan() as f64,
   )
@@ -31,8 +59,8 @@ pub fn manhattan_a_star(
 /// could be found, `None` is returned.
 ///
 /// The provided functions serve the following purposes:
-/// - `can_walk` returns true if a particular point is accessible for the
-///   purposes of this search.
+/// - `can_step` returns true if it is possible to step from the first point to
+///   the second, which is one of its eight neighbors.
 /// - `distance` measures the distance between two points. Manhattan distance is
 ///   recommended here.
 /// - `heuristc` is the A* heuristic function, which roughly describes the cost
@@ -43,28 +71,10 @@ pub fn manhattan_a_star(
 pub fn a_star(
   start: Point,
   goal: Point,
-  mut can_walk: impl FnMut(Point) -> bool,
+  mut can_step: impl FnMut(Point, Point) -> bool,
   mut distance: impl FnMut(Point, Point) -> f64,
   mut heuristic: impl FnMut(Point) -> f64,
 ) -> Option<Vec<Point>> {
-  #[derive(Copy, Clone)]
-  struct Node(f64, Point);
-  impl PartialEq for Node {
-    fn eq(&self, other: &Self) -> bool {
-      self.0 == other.0
-    }
-  }
-  impl Eq for Node {}
-  impl PartialOrd for Node {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-      self.0.partial_cmp(&other.0).map(Ordering::reverse)
-    }
-  }
-  impl Ord for Node {
-    fn cmp(&self, other: &Self) -> Ordering {
-      self.partial_cmp(other).unwrap_or(Ordering::Less)
-    }
-  }
   let mut open_nodes = BinaryHeap::<Node>::new();
 
   let mut came_from = HashMap::new();
@@ -86,7 +96,7 @@ pub fn a_star(
 
     for &d in &Dir::all() {
       let neighbor = current + d.to_point::<i64>();
-      if !can_walk(neighbor) {
+      if !can_step(current, neighbor) {
         continue;
       }
 
@@ -104,3 +114,437 @@ pub fn a_star(
 
   None
 }
+
+/// A distance field, also known as a "Dijkstra map".
+///
+/// A `DistanceMap` records, for every point in some region, the cost of
+/// walking from that point to the nearest of a set of goals. Any number of
+/// actors can then approach the goals by repeatedly stepping to the neighbor
+/// with the lowest value (see [`DistanceMap::downhill()`]), without each of
+/// them needing to pathfind separately.
+///
+/// Fleeing works the same way, using a map built with [`DistanceMap::flee()`].
+#[derive(Clone, Debug)]
+pub struct DistanceMap(RectVec<f64>);
+
+impl DistanceMap {
+  /// Computes the distance from every point of `bounds` to the nearest of
+  /// `goals`.
+  ///
+  /// Only points for which `can_walk` returns true are included; `distance`
+  /// gives the cost of stepping between two adjacent points, just like in
+  /// [`a_star()`]. Paths never leave `bounds`.
+  pub fn new(
+    bounds: Rect,
+    goals: impl IntoIterator<Item = Point>,
+    mut can_walk: impl FnMut(Point) -> bool,
+    distance: impl FnMut(Point, Point) -> f64,
+  ) -> Self {
+    let mut map = RectVec::new(bounds, f64::INFINITY);
+    for goal in goals {
+      if !can_walk(goal) {
+        continue;
+      }
+      if let Some(d) = map.get_mut(goal) {
+        *d = 0.0;
+      }
+    }
+
+    let mut map = Self(map);
+    map.relax(can_walk, distance);
+    map
+  }
+
+  /// Builds a map for fleeing from the goals of this one.
+  ///
+  /// Simply walking uphill on an approach map tends to get actors cornered, so
+  /// instead, every distance is multiplied by `-scale` and the map is
+  /// recomputed, which makes actors willing to run past the goals towards
+  /// farther-away escape routes. `scale` should be somewhat larger than one;
+  /// 1.2 is a good start. The `can_walk` and `distance` functions should be
+  /// the ones this map was built with.
+  pub fn flee(
+    &self,
+    scale: f64,
+    can_walk: impl FnMut(Point) -> bool,
+    distance: impl FnMut(Point, Point) -> f64,
+  ) -> Self {
+    let mut map = self.clone();
+    for d in map.0.data_mut() {
+      if d.is_finite() {
+        *d *= -scale;
+      }
+    }
+    map.relax(can_walk, distance);
+    map
+  }
+
+  /// Returns the region this map covers.
+  pub fn bounds(&self) -> Rect {
+    self.0.dims()
+  }
+
+  /// Returns the value of this map at `p`, if `p` can reach a goal.
+  pub fn get(&self, p: Point) -> Option<f64> {
+    self.0.get(p).cloned().filter(|d| d.is_finite())
+  }
+
+  /// Returns the neighbor of `p` with the lowest value, if it is lower than
+  /// the value at `p` itself.
+  ///
+  /// Neighbors in `occupied` are skipped, so that an actor whose best step is
+  /// blocked will take the next best one instead, as long as it still goes
+  /// downhill.
+  ///
+  /// Repeatedly stepping downhill from any point leads to a goal, or, for a
+  /// flee map, as far away from the goals as possible.
+  pub fn downhill(&self, p: Point, occupied: &PointSet) -> Option<Point> {
+    let mut best = (self.get(p)?, None);
+    for &d in &Dir::all() {
+      let n = p + d.to_point::<i64>();
+      if occupied.contains(n) {
+        continue;
+      }
+      match self.get(n) {
+        Some(v) if v < best.0 => best = (v, Some(n)),
+        _ => {}
+      }
+    }
+    best.1
+  }
+
+  /// Runs Dijkstra's algorithm outwards from every finite point, until every
+  /// point holds the lowest cost reachable from its neighbors.
+  fn relax(
+    &mut self,
+    mut can_walk: impl FnMut(Point) -> bool,
+    mut distance: impl FnMut(Point, Point) -> f64,
+  ) {
+    let mut open_nodes = self
+      .0
+      .points()
+      .filter(|(_, d)| d.is_finite())
+      .map(|(p, &d)| Node(d, p))
+      .collect::<BinaryHeap<_>>();
+
+    while let Some(Node(d, current)) = open_nodes.pop() {
+      if d > self.0.get(current).cloned().unwrap_or(f64::INFINITY) {
+        // This node was already reached more cheaply.
+        continue;
+      }
+
+      for &dir in &Dir::all() {
+        let neighbor = current + dir.to_point::<i64>();
+        let slot = match self.0.get(neighbor) {
+          Some(&slot) => slot,
+          None => continue,
+        };
+
+        let tentative = d + distance(current, neighbor);
+        if tentative < slot && can_walk(neighbor) {
+          *self.0.get_mut(neighbor).unwrap() = tentative;
+          open_nodes.push(Node(tentative, neighbor));
+        }
+      }
+    }
+  }
+}
+
+/// Computes the Gabriel graph of `points`, returning its edges as pairs of
+/// indices into `points`.
+///
+/// Two points are joined if no other point lies within the circle whose
+/// diameter is the segment between them. The result is a planar graph that
+/// connects nearby points to each other, but never joins two points "through"
+/// a third; it always contains the (Euclidean) minimum spanning tree.
+pub fn gabriel_graph(points: &[Point]) -> Vec<(usize, usize)> {
+  let dist2 = |a: Point, b: Point| {
+    let d = a - b;
+    d.x() * d.x() + d.y() * d.y()
+  };
+
+  let mut edges = Vec::new();
+  for (i, &a) in points.iter().enumerate() {
+    for (j, &b) in points.iter().enumerate().skip(i + 1) {
+      let ab = dist2(a, b);
+      let is_blocked = points
+        .iter()
+        .enumerate()
+        .filter(|&(k, _)| k != i && k != j)
+        .any(|(_, &c)| dist2(a, c) + dist2(b, c) < ab);
+      if !is_blocked {
+        edges.push((i, j));
+      }
+    }
+  }
+  edges
+}
+
+/// Computes a minimum spanning tree of the graph on `n` nodes with the given
+/// `edges`, using Kruskal's algorithm.
+///
+/// Returns the subset of `edges` that makes up the tree. If the graph is not
+/// connected, this is a spanning forest instead.
+pub fn spanning_tree(
+  n: usize,
+  edges: &[(usize, usize)],
+  mut weight: impl FnMut(usize, usize) -> f64,
+) -> Vec<(usize, usize)> {
+  let mut sorted = edges
+    .iter()
+    .map(|&(a, b)| (weight(a, b), (a, b)))
+    .collect::<Vec<_>>();
+  sorted.sort_by(|(x, _), (y, _)| x.partial_cmp(y).unwrap_or(Ordering::Equal));
+
+  // A union-find forest over the nodes, for telling whether an edge would
+  // close a cycle.
+  let mut parents = (0..n).collect::<Vec<_>>();
+  fn root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+      parents[i] = parents[parents[i]];
+      i = parents[i];
+    }
+    i
+  }
+
+  let mut tree = Vec::new();
+  for (_, (a, b)) in sorted {
+    let (ra, rb) = (root(&mut parents, a), root(&mut parents, b));
+    if ra != rb {
+      parents[ra] = rb;
+      tree.push((a, b));
+    }
+  }
+  tree
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::rng::Stream;
+  use rand::Rng as _;
+
+  /// The step cost used throughout: Manhattan distance, so that a diagonal
+  /// step is worth two orthogonal ones.
+  fn manhattan(a: Point, b: Point) -> f64 {
+    (a - b).manhattan() as f64
+  }
+
+  /// Follows `map` downhill from `start` until it bottoms out, returning every
+  /// point along the way.
+  fn walk(map: &DistanceMap, start: Point) -> Vec<Point> {
+    let mut path = vec![start];
+    while let Some(next) = map.downhill(*path.last().unwrap(), &PointSet::new())
+    {
+      path.push(next);
+      assert!(path.len() < 1000, "walked in circles from {:?}", start);
+    }
+    path
+  }
+
+  #[test]
+  fn distances_from_several_goals() {
+    let bounds = Rect::with_dims(20, 10);
+    let goals = [Point::new(0, 0), Point::new(19, 9), Point::new(12, 2)];
+    // A wall down the middle, with a gap at the bottom.
+    let wall = |p: Point| p.x() == 6 && p.y() < 9;
+    let map =
+      DistanceMap::new(bounds, goals.iter().copied(), |p| !wall(p), manhattan);
+
+    assert_eq!(map.bounds(), bounds);
+    for p in bounds.points() {
+      if wall(p) {
+        assert_eq!(map.get(p), None);
+        continue;
+      }
+      // Left of the wall, every point is straight on from the first goal.
+      if p.x() < 6 {
+        assert_eq!(map.get(p), Some(p.manhattan() as f64), "{:?}", p);
+        continue;
+      }
+      let nearest = goals[1..]
+        .iter()
+        .map(|&g| (p - g).manhattan())
+        .min()
+        .unwrap();
+      assert_eq!(map.get(p), Some(nearest as f64), "{:?}", p);
+    }
+    for &goal in &goals {
+      assert_eq!(map.get(goal), Some(0.0));
+    }
+
+    // Goals outside of the bounds, or that can't be walked on, are ignored.
+    let map = DistanceMap::new(
+      bounds,
+      vec![Point::new(6, 0), Point::new(-5, 0)],
+      |p| !wall(p),
+      manhattan,
+    );
+    assert!(bounds.points().all(|p| map.get(p).is_none()));
+  }
+
+  #[test]
+  fn downhill_steps_around_occupied() {
+    let bounds = Rect::with_dims(10, 10);
+    let map =
+      DistanceMap::new(bounds, Some(Point::new(0, 5)), |_| true, manhattan);
+    let start = Point::new(3, 5);
+    let points = |ps: &[(i64, i64)]| {
+      ps.iter()
+        .map(|&(x, y)| Point::new(x, y))
+        .collect::<PointSet>()
+    };
+
+    assert_eq!(
+      map.downhill(start, &PointSet::new()),
+      Some(Point::new(2, 5))
+    );
+    // With the way straight on blocked, the next best steps are diagonal,
+    // which don't get any closer under Manhattan distance.
+    assert_eq!(map.downhill(start, &points(&[(2, 5)])), None);
+
+    // Goals are a dead end.
+    assert_eq!(map.downhill(Point::new(0, 5), &PointSet::new()), None);
+    // So is anywhere that can't reach a goal.
+    assert_eq!(map.downhill(Point::new(30, 30), &PointSet::new()), None);
+
+    // Under Chebyshev distance, a diagonal step is as good as any other, so a
+    // blocked actor sidesteps instead.
+    let map =
+      DistanceMap::new(bounds, Some(Point::new(0, 5)), |_| true, |_, _| 1.0);
+    assert_eq!(
+      map.downhill(start, &points(&[(2, 5)])).map(|p| p.x()),
+      Some(2)
+    );
+    assert_eq!(
+      map.downhill(start, &points(&[(2, 4), (2, 5), (2, 6)])),
+      None
+    );
+
+    // Every walk downhill ends at the goal.
+    for p in bounds.points() {
+      assert_eq!(walk(&map, p).last(), Some(&Point::new(0, 5)));
+    }
+  }
+
+  #[test]
+  fn flee_leads_away_from_goals() {
+    // A long corridor, with the goal near its left end. Fleeing uphill would
+    // get anything between the goal and the left end cornered; a flee map
+    // sends it past the goal to the other end instead.
+    let bounds = Rect::with_dims(41, 1);
+    let goal = Point::new(10, 0);
+    let approach = DistanceMap::new(bounds, Some(goal), |_| true, manhattan);
+    let flee = approach.flee(1.2, |_| true, manhattan);
+
+    let path = walk(&flee, Point::new(9, 0));
+    assert!(path.contains(&goal));
+    assert_eq!(path.last(), Some(&Point::new(40, 0)));
+
+    // Out in the open, fleeing always ends up farther away than it started,
+    // unless it started somewhere there's no getting away from.
+    let bounds = Rect::with_dims(30, 30);
+    let goal = Point::new(12, 17);
+    let approach = DistanceMap::new(bounds, Some(goal), |_| true, manhattan);
+    let flee = approach.flee(1.2, |_| true, manhattan);
+    for p in bounds.points() {
+      let path = walk(&flee, p);
+      if path.len() == 1 {
+        assert_ne!(p, goal);
+        continue;
+      }
+      let end = *path.last().unwrap();
+      assert!(
+        approach.get(end).unwrap() > approach.get(p).unwrap(),
+        "fleeing from {:?} ended up at {:?}",
+        p,
+        end
+      );
+    }
+  }
+
+  fn points(ps: &[(i64, i64)]) -> Vec<Point> {
+    ps.iter().map(|&(x, y)| Point::new(x, y)).collect()
+  }
+
+  fn sorted(mut edges: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    for e in &mut edges {
+      *e = (e.0.min(e.1), e.0.max(e.1));
+    }
+    edges.sort_unstable();
+    edges
+  }
+
+  #[test]
+  fn gabriel_edges() {
+    // Points in a row are only joined to their neighbors.
+    let row = points(&[(0, 0), (5, 0), (10, 0), (17, 0)]);
+    assert_eq!(gabriel_graph(&row), vec![(0, 1), (1, 2), (2, 3)]);
+
+    // In an obtuse triangle, the long side is blocked by the third corner.
+    let obtuse = points(&[(0, 0), (10, 0), (5, 1)]);
+    assert_eq!(gabriel_graph(&obtuse), vec![(0, 2), (1, 2)]);
+    let acute = points(&[(0, 0), (10, 0), (5, 9)]);
+    assert_eq!(gabriel_graph(&acute), vec![(0, 1), (0, 2), (1, 2)]);
+
+    // A point just off the middle of a square blocks both diagonals and the
+    // side nearest to it, but none of the other sides.
+    let square = points(&[(0, 0), (10, 0), (0, 10), (10, 10), (5, 4)]);
+    assert_eq!(
+      sorted(gabriel_graph(&square)),
+      vec![(0, 2), (0, 4), (1, 3), (1, 4), (2, 3), (2, 4), (3, 4)]
+    );
+
+    assert!(gabriel_graph(&[]).is_empty());
+    assert!(gabriel_graph(&points(&[(3, 3)])).is_empty());
+  }
+
+  #[test]
+  fn spanning_tree_edges() {
+    let dist = |ps: &[Point], a: usize, b: usize| {
+      let d = ps[a] - ps[b];
+      ((d.x() * d.x() + d.y() * d.y()) as f64).sqrt()
+    };
+
+    // A lopsided square with its diagonals: the tree takes the three shortest
+    // sides.
+    let square = points(&[(0, 0), (10, 0), (0, 11), (10, 12)]);
+    let all = vec![(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)];
+    let tree = spanning_tree(4, &all, |a, b| dist(&square, a, b));
+    assert_eq!(sorted(tree), vec![(0, 1), (0, 2), (2, 3)]);
+
+    // A disconnected graph gets a forest.
+    let tree = spanning_tree(5, &[(0, 1), (1, 2), (0, 2), (3, 4)], |a, b| {
+      (a + b) as f64
+    });
+    assert_eq!(sorted(tree), vec![(0, 1), (0, 2), (3, 4)]);
+
+    // The Gabriel graph always contains a minimum spanning tree of the
+    // complete graph.
+    let mut rng = Stream::new(5, 0);
+    for _ in 0..20 {
+      let ps = (0..30)
+        .map(|_| Point::new(rng.gen_range(-50..50), rng.gen_range(-50..50)))
+        .collect::<Vec<_>>();
+      let complete = (0..ps.len())
+        .flat_map(|a| (a + 1..ps.len()).map(move |b| (a, b)))
+        .collect::<Vec<_>>();
+      let weight = |tree: Vec<(usize, usize)>| {
+        tree.iter().map(|&(a, b)| dist(&ps, a, b)).sum::<f64>()
+      };
+
+      let gabriel = gabriel_graph(&ps);
+      let expected =
+        weight(spanning_tree(ps.len(), &complete, |a, b| dist(&ps, a, b)));
+      let actual =
+        weight(spanning_tree(ps.len(), &gabriel, |a, b| dist(&ps, a, b)));
+      assert!(
+        (actual - expected).abs() < 1e-9,
+        "{} vs {}",
+        actual,
+        expected
+      );
+    }
+  }
+}
@@ -0,0 +1,355 @@
+//! Straight lines across the grid, for projectiles and targeting.
+
+use crate::geo::Point;
+use crate::geo::PointSet;
+
+/// Returns an iterator over the points of the Bresenham line from `from` to
+/// `to`, including both endpoints.
+///
+/// Bresenham lines take exactly one step along their longer axis at a time,
+/// so they are the thinnest lines that have no gaps in them, with diagonal
+/// steps counting as connected. They are not symmetric: the line from `to` to
+/// `from` may pass through different points.
+pub fn bresenham(from: Point, to: Point) -> Bresenham {
+  let d = to - from;
+  Bresenham {
+    pos: from,
+    to,
+    step: Point::new(d.x().signum(), d.y().signum()),
+    delta: Point::new(d.x().abs(), -d.y().abs()),
+    err: d.x().abs() - d.y().abs(),
+    done: false,
+  }
+}
+
+/// An iterator over a Bresenham line; see [`bresenham()`].
+#[derive(Clone, Debug)]
+pub struct Bresenham {
+  pos: Point,
+  to: Point,
+  step: Point,
+  // The absolute value of the line's x extent, and the *negated* absolute
+  // value of its y extent.
+  delta: Point,
+  err: i64,
+  done: bool,
+}
+
+impl Iterator for Bresenham {
+  type Item = Point;
+
+  fn next(&mut self) -> Option<Point> {
+    if self.done {
+      return None;
+    }
+
+    let pos = self.pos;
+    if pos == self.to {
+      self.done = true;
+      return Some(pos);
+    }
+
+    let e2 = 2 * self.err;
+    if e2 >= self.delta.y() {
+      self.err += self.delta.y();
+      self.pos += Point::new(self.step.x(), 0);
+    }
+    if e2 <= self.delta.x() {
+      self.err += self.delta.x();
+      self.pos += Point::new(0, self.step.y());
+    }
+    Some(pos)
+  }
+}
+
+/// Returns an iterator over the points of the supercover line from `from` to
+/// `to`, including both endpoints.
+///
+/// The supercover line contains every tile that the straight line between the
+/// centers of `from` and `to` touches, so that it never squeezes diagonally
+/// between two tiles; when the line passes exactly through the corner of a
+/// tile, all of the tiles around that corner are included.
+pub fn supercover(from: Point, to: Point) -> Supercover {
+  let d = to - from;
+  Supercover {
+    pos: from,
+    step: Point::new(d.x().signum(), d.y().signum()),
+    len: Point::new(d.x().abs(), d.y().abs()),
+    taken: Point::zero(),
+    pending: [Some(from), None],
+  }
+}
+
+/// An iterator over a supercover line; see [`supercover()`].
+#[derive(Clone, Debug)]
+pub struct Supercover {
+  pos: Point,
+  step: Point,
+  len: Point,
+  // The number of steps taken along each axis so far.
+  taken: Point,
+  // Points to yield before taking another step.
+  pending: [Option<Point>; 2],
+}
+
+impl Iterator for Supercover {
+  type Item = Point;
+
+  fn next(&mut self) -> Option<Point> {
+    if let Some(p) = self.pending.iter_mut().find_map(Option::take) {
+      return Some(p);
+    }
+
+    let [nx, ny] = self.len.coords();
+    let [ix, iy] = self.taken.coords();
+    if ix >= nx && iy >= ny {
+      return None;
+    }
+
+    // Compare where the line crosses the next vertical and horizontal tile
+    // boundaries, scaled by 2 * nx * ny to keep everything integral.
+    let [sx, sy] = self.step.coords();
+    let decision = (1 + 2 * ix) * ny - (1 + 2 * iy) * nx;
+    let step = match decision {
+      0 => {
+        // The line passes exactly through a corner, so it touches the tiles
+        // on both sides of it on its way to the diagonal one.
+        let side = self.pos + Point::new(sx, 0);
+        self.pending = [
+          Some(self.pos + Point::new(0, sy)),
+          Some(self.pos + self.step),
+        ];
+        self.pos += self.step;
+        self.taken += Point::new(1, 1);
+        return Some(side);
+      }
+      d if d < 0 => Point::new(sx, 0),
+      _ => Point::new(0, sy),
+    };
+    self.taken += Point::new(step.x().abs(), step.y().abs());
+    self.pos += step;
+    Some(self.pos)
+  }
+}
+
+/// How many different offsets [`line_of_fire()`] tries at each end of a line
+/// before giving up.
+const FAN_WIDTH: i64 = 16;
+
+/// Finds a clear straight path for a projectile from `from` to `to`.
+///
+/// A path is only found if `to` is in `visible`, which should be everything in
+/// view from `from`, such as the [`Fov::visible`] set of whoever is firing,
+/// and no tile along the way is opaque, so that nothing can be fired at unless
+/// it can be seen. Since a single line can't reach everything that is in view,
+/// this tries a fan of slightly offset lines between the two tiles, starting
+/// with the [`bresenham()`] line, and returns the first one that is clear.
+///
+/// With [`fov::Shadowcast`] and [`fov::Permissive`], this finds a path to
+/// everything in view. [`fov::Milazzo`] bevels the corners of walls, so it can
+/// see a little way around them where no path of whole tiles can go.
+///
+/// The returned path starts with `from` and ends with `to`.
+///
+/// [`Fov::visible`]: crate::actor::ai::Fov::visible
+/// [`fov::Shadowcast`]: crate::geo::fov::Shadowcast
+/// [`fov::Permissive`]: crate::geo::fov::Permissive
+/// [`fov::Milazzo`]: crate::geo::fov::Milazzo
+pub fn line_of_fire(
+  from: Point,
+  to: Point,
+  visible: &PointSet,
+  is_opaque: &mut dyn FnMut(Point) -> bool,
+) -> Option<Vec<Point>> {
+  if !visible.contains(to) {
+    return None;
+  }
+
+  let mut is_clear = |p: Point| p == to || !is_opaque(p);
+  let path = bresenham(from, to).collect::<Vec<_>>();
+  if path.iter().skip(1).all(|&p| is_clear(p)) {
+    return Some(path);
+  }
+
+  // Offset each end of the line across its tile, perpendicular to the line's
+  // longer axis, and round what it passes through back to whole tiles.
+  let offsets = (0..FAN_WIDTH)
+    .map(|i| (i as f64 + 0.5) / FAN_WIDTH as f64 - 0.5)
+    .collect::<Vec<_>>();
+  for &start in &offsets {
+    for &end in &offsets {
+      let path = offset_line(from, to, start, end);
+      if path.iter().skip(1).all(|&p| is_clear(p)) {
+        return Some(path);
+      }
+    }
+  }
+  None
+}
+
+/// Returns the tiles along the line from `from` to `to` with its ends shifted
+/// by `start` and `end` across the line's shorter axis, taking exactly one
+/// step along its longer axis at a time.
+///
+/// The offsets should lie strictly between -0.5 and 0.5, so that the line
+/// still starts and ends at `from` and `to`.
+fn offset_line(from: Point, to: Point, start: f64, end: f64) -> Vec<Point> {
+  let d = to - from;
+  let swap = d.y().abs() > d.x().abs();
+  let (major, minor) = match swap {
+    false => (d.x(), d.y()),
+    true => (d.y(), d.x()),
+  };
+
+  let len = major.abs();
+  (0..=len)
+    .map(|i| {
+      let t = if len == 0 { 0.0 } else { i as f64 / len as f64 };
+      let along = i * major.signum();
+      let across = (start + (minor as f64 - start + end) * t).round() as i64;
+      match swap {
+        false => from + Point::new(along, across),
+        true => from + Point::new(across, along),
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rand::Rng as _;
+
+  use crate::geo::fov;
+  use crate::geo::fov::Algorithm;
+  use crate::geo::Rect;
+  use crate::rng;
+
+  /// A spread of endpoints in every direction, including the degenerate ones.
+  fn endpoints() -> Vec<(Point, Point)> {
+    let from = Point::new(-3, 2);
+    Rect::new(Point::new(-10, -8), Point::new(7, 9))
+      .points()
+      .map(|to| (from, to))
+      .collect()
+  }
+
+  fn assert_no_duplicates(line: &[Point]) {
+    let set = line.iter().copied().collect::<PointSet>();
+    assert_eq!(set.len(), line.len(), "{:?}", line);
+  }
+
+  #[test]
+  fn bresenham_lines() {
+    for (from, to) in endpoints() {
+      let line = bresenham(from, to).collect::<Vec<_>>();
+      assert_eq!(line.first(), Some(&from));
+      assert_eq!(line.last(), Some(&to));
+
+      let d = to - from;
+      let len = d.x().abs().max(d.y().abs()) + 1;
+      assert_eq!(line.len() as i64, len, "{:?} -> {:?}", from, to);
+      assert_no_duplicates(&line);
+      for w in line.windows(2) {
+        let step = w[1] - w[0];
+        assert!(step.x().abs() <= 1 && step.y().abs() <= 1, "{:?}", line);
+      }
+    }
+  }
+
+  #[test]
+  fn supercover_lines() {
+    for (from, to) in endpoints() {
+      let line = supercover(from, to).collect::<Vec<_>>();
+      assert_eq!(line.first(), Some(&from));
+      assert_eq!(line.last(), Some(&to));
+
+      // Every step along either axis adds a tile, and every corner the line
+      // passes through adds one more.
+      let d = to - from;
+      let min_len = d.x().abs() + d.y().abs() + 1;
+      assert!(line.len() as i64 >= min_len, "{:?} -> {:?}", from, to);
+      assert_no_duplicates(&line);
+
+      // The supercover contains the Bresenham line.
+      let set = line.iter().copied().collect::<PointSet>();
+      for p in bresenham(from, to) {
+        assert!(set.contains(p), "{:?} -> {:?}: {:?}", from, to, p);
+      }
+    }
+
+    // Lines that never pass through a corner have no extra tiles.
+    let line = supercover(Point::zero(), Point::new(5, 2)).collect::<Vec<_>>();
+    assert_eq!(line.len(), 8);
+  }
+
+  #[test]
+  fn supercover_touches_corners() {
+    let line = supercover(Point::zero(), Point::new(2, 2)).collect::<Vec<_>>();
+    let expected = [(0, 0), (1, 0), (0, 1), (1, 1), (2, 1), (1, 2), (2, 2)]
+      .iter()
+      .map(|&(x, y)| Point::new(x, y))
+      .collect::<Vec<_>>();
+    assert_eq!(line, expected);
+
+    // This one passes through a single corner, halfway along.
+    let line = supercover(Point::zero(), Point::new(-3, 1)).collect::<Vec<_>>();
+    assert_no_duplicates(&line);
+    assert_eq!(line.len(), 6);
+    assert!(line.contains(&Point::new(-2, 0)));
+    assert!(line.contains(&Point::new(-1, 1)));
+  }
+
+  /// Checks that [`line_of_fire()`] finds a clear path to everything that
+  /// `algorithm` reports as visible, on a handful of random maps.
+  fn assert_line_of_fire(algorithm: &dyn Algorithm) {
+    for seed in 0..5 {
+      let mut rng = rng::Rng::new(seed);
+      let rng = rng.stream("line");
+      let bounds = Rect::with_dims(16, 16);
+      let walls = bounds
+        .points()
+        .filter(|_| rng.gen_bool(0.3))
+        .collect::<PointSet>();
+      let is_opaque = &mut |p| !bounds.contains(p) || walls.contains(p);
+
+      for from in bounds.points().filter(|&p| !walls.contains(p)) {
+        let mut visible = PointSet::new();
+        let range = Point::new(100, 100);
+        algorithm.compute(from, range, is_opaque, &mut |p| {
+          visible.insert(p);
+        });
+
+        for to in visible.iter() {
+          let path = line_of_fire(from, to, &visible, is_opaque);
+          let path = path.unwrap_or_else(|| {
+            panic!("{}: no path {:?} -> {:?}", algorithm.name(), from, to)
+          });
+          assert_eq!(path.first(), Some(&from));
+          assert_eq!(path.last(), Some(&to));
+          let mid = path.iter().filter(|&&p| p != from && p != to);
+          assert!(mid.copied().all(|p| !is_opaque(p)), "{:?}", path);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn line_of_fire_reaches_shadowcast() {
+    assert_line_of_fire(&fov::Shadowcast);
+  }
+
+  #[test]
+  fn line_of_fire_reaches_permissive() {
+    assert_line_of_fire(&fov::Permissive);
+  }
+
+  #[test]
+  fn line_of_fire_needs_visibility() {
+    let visible = PointSet::new();
+    let path =
+      line_of_fire(Point::zero(), Point::new(3, 1), &visible, &mut |_| false);
+    assert_eq!(path, None);
+  }
+}
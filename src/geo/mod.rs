@@ -14,9 +14,13 @@ use num::ToPrimitive;
 use num::Zero;
 
 mod impls;
+mod point_set;
 
 pub mod fov;
 pub mod graph;
+pub mod line;
+
+pub use point_set::PointSet;
 
 /// A cardinal direction on the plane.
 /// 
@@ -52,6 +56,34 @@ impl Dir {
     Point::new(x, y)
   }
 
+  /// Returns the direction that `p` points in, rounded to the nearest
+  /// orthogonal or diagonal direction.
+  ///
+  /// Returns `None` if `p` is zero.
+  pub fn from_point(p: Point<i64>) -> Option<Dir> {
+    use Dir::*;
+
+    // A coordinate only counts if it's at least tan(22.5°) = sqrt(2) - 1 times
+    // the other one, i.e. if `p` is within 22.5° of a diagonal; this is exact,
+    // since sqrt(2) is irrational.
+    let (x, y) = (p.x().abs() as i128, p.y().abs() as i128);
+    let counts = |a: i128, b: i128| (a + b) * (a + b) >= 2 * b * b;
+    let sx = if counts(x, y) { p.x().signum() } else { 0 };
+    let sy = if counts(y, x) { p.y().signum() } else { 0 };
+
+    match (sx, sy) {
+      (0, -1) => Some(N),
+      (-1, 0) => Some(W),
+      (1, 0) => Some(E),
+      (0, 1) => Some(S),
+      (-1, -1) => Some(Nw),
+      (1, -1) => Some(Ne),
+      (-1, 1) => Some(Sw),
+      (1, 1) => Some(Se),
+      _ => None,
+    }
+  }
+
   /// Returns whether `self` is an orthgonal direction.
   pub fn is_ortho(self) -> bool {
     use Dir::*;
@@ -338,9 +370,7 @@ impl<T: Signed> Rect<T> {
     let [x1, y1] = self.0.coords();
     let [x2, y2] = self.1.coords();
 
-    (y1..y2)
-      .map(move |y| (x1..x2).map(move |x| Point::new(x, y)))
-      .flatten()
+    (y1..y2).flat_map(move |y| (x1..x2).map(move |x| Point::new(x, y)))
   }
 
   /// Returns an iterator over all points in the boundary of this rectangle.
@@ -358,7 +388,7 @@ impl<T: Signed> Rect<T> {
       .map(move |x| Point::new(x, y1))
       .chain(
         (y1.add(T::one())..y2.sub(T::one()))
-          .map(move |y| {
+          .flat_map(move |y| {
             let mut count = 0;
             std::iter::from_fn(move || match count {
               0 => {
@@ -371,8 +401,7 @@ impl<T: Signed> Rect<T> {
               }
               _ => None,
             })
-          })
-          .flatten(),
+          }),
       )
       .chain((x1..x2).map(move |x| Point::new(x, y2.sub(T::one()))))
   }
@@ -411,16 +440,17 @@ impl<T: Signed> Rect<T> {
 
     (start.y()..end.y())
       .step_by(tile_dims.y().to_usize().unwrap())
-      .map(move |y| {
+      .flat_map(move |y| {
         (start.x()..end.x())
           .step_by(tile_dims.x().to_usize().unwrap())
-          .map(move |x| {
+          .filter_map(move |x| {
+            // If `self` is empty, it may still span some tiles along one axis,
+            // but it intersects none of them.
             let tile_coords = Point::new(x, y);
             let tile = Self::new(tile_coords, tile_coords + tile_dims);
-            self.intersect(tile).unwrap() + tile_start
+            Some(self.intersect(tile)? + tile_start)
           })
       })
-      .flatten()
   }
 }
 
@@ -518,3 +548,25 @@ impl<T: Clone> RectVec<T> {
       .map(move |(i, p)| (p, unsafe { &mut *ptr.add(i) }))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn dir_from_point_rounds_to_nearest() {
+    for &d in &Dir::all() {
+      assert_eq!(Dir::from_point(d.to_point::<i64>()), Some(d));
+      assert_eq!(Dir::from_point(d.to_point::<i64>() * 7), Some(d));
+    }
+    assert_eq!(Dir::from_point(Point::zero()), None);
+
+    assert_eq!(Dir::from_point(Point::new(5, -1)), Some(Dir::E));
+    assert_eq!(Dir::from_point(Point::new(5, -2)), Some(Dir::E));
+    assert_eq!(Dir::from_point(Point::new(5, -3)), Some(Dir::Ne));
+    assert_eq!(Dir::from_point(Point::new(-1, 10)), Some(Dir::S));
+    assert_eq!(Dir::from_point(Point::new(-4, 10)), Some(Dir::S));
+    assert_eq!(Dir::from_point(Point::new(-5, 10)), Some(Dir::Sw));
+    assert_eq!(Dir::from_point(Point::new(-9, -10)), Some(Dir::Nw));
+  }
+}
@@ -0,0 +1,471 @@
+//! Sets of points, stored as bitmaps.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::iter::FromIterator;
+
+use crate::geo::Point;
+use crate::geo::Rect;
+use crate::map;
+use crate::save;
+use crate::save::Decode;
+use crate::save::Encode;
+
+/// The width and height of each block of a [`PointSet`], which matches the
+/// size of a map chunk.
+const WIDTH: i64 = map::WIDTH as i64;
+
+/// The number of words in each block of a [`PointSet`].
+const WORDS: usize = (WIDTH * WIDTH) as usize / 64;
+
+/// A block of bits, one for each point of a `WIDTH` by `WIDTH` square, in
+/// row-major order.
+type Block = [u64; WORDS];
+
+/// Returns the upper-left corner of the block containing `p`, and the index of
+/// `p`'s bit within it.
+fn locate(p: Point) -> (Point, usize) {
+  let [x, y] = p.coords();
+  let origin = Point::new(x.div_euclid(WIDTH), y.div_euclid(WIDTH)) * WIDTH;
+  let offset = p - origin;
+  (origin, (offset.y() * WIDTH + offset.x()) as usize)
+}
+
+/// A set of points on the plane.
+///
+/// Points are grouped into square blocks, aligned the same way as the chunks
+/// of a [`Floor`], each of which is stored as a bitmap. This makes building,
+/// querying and iterating over large, dense regions, like fields of view, much
+/// cheaper than with a `HashSet<Point>`.
+///
+/// Iteration order is unspecified.
+///
+/// [`Floor`]: crate::map::Floor
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct PointSet {
+  // Invariant: no block is all zeroes.
+  blocks: HashMap<Point, Block>,
+  len: usize,
+}
+
+impl PointSet {
+  /// Creates a new, empty `PointSet`.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Returns the number of points in this set.
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  /// Returns whether this set has no points in it.
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// Removes every point from this set.
+  pub fn clear(&mut self) {
+    self.blocks.clear();
+    self.len = 0;
+  }
+
+  /// Returns whether `p` is in this set.
+  pub fn contains(&self, p: Point) -> bool {
+    let (origin, bit) = locate(p);
+    match self.blocks.get(&origin) {
+      Some(block) => block[bit / 64] & (1 << (bit % 64)) != 0,
+      None => false,
+    }
+  }
+
+  /// Adds `p` to this set, returning whether it was newly added.
+  pub fn insert(&mut self, p: Point) -> bool {
+    let (origin, bit) = locate(p);
+    let word = &mut self.blocks.entry(origin).or_insert([0; WORDS])[bit / 64];
+    let mask = 1 << (bit % 64);
+    let is_new = *word & mask == 0;
+    *word |= mask;
+    self.len += is_new as usize;
+    is_new
+  }
+
+  /// Removes `p` from this set, returning whether it was there.
+  pub fn remove(&mut self, p: Point) -> bool {
+    let (origin, bit) = locate(p);
+    let block = match self.blocks.get_mut(&origin) {
+      Some(block) => block,
+      None => return false,
+    };
+
+    let mask = 1 << (bit % 64);
+    let was_present = block[bit / 64] & mask != 0;
+    block[bit / 64] &= !mask;
+    if block.iter().all(|&w| w == 0) {
+      self.blocks.remove(&origin);
+    }
+    self.len -= was_present as usize;
+    was_present
+  }
+
+  /// Returns an iterator over every point in this set.
+  pub fn iter(&self) -> impl Iterator<Item = Point> + '_ {
+    self
+      .blocks
+      .iter()
+      .flat_map(|(&origin, block)| block_points(origin, block))
+  }
+
+  /// Returns an iterator over every point in this set that lies within `rect`.
+  pub fn iter_in(&self, rect: Rect) -> impl Iterator<Item = Point> + '_ {
+    rect
+      .disect(Rect::with_dims(WIDTH, WIDTH))
+      .filter_map(move |r| {
+        let block = self.blocks.get(&locate(r.upper_left()).0)?;
+        Some((r, block))
+      })
+      .flat_map(|(r, block)| {
+        block_points(locate(r.upper_left()).0, block)
+          .filter(move |&p| r.contains(p))
+      })
+  }
+
+  /// Returns whether any point of this set lies within `rect`.
+  pub fn intersects(&self, rect: Rect) -> bool {
+    self.iter_in(rect).next().is_some()
+  }
+
+  /// Adds every point of `other` to this set.
+  pub fn union_with(&mut self, other: &PointSet) {
+    for (&origin, theirs) in &other.blocks {
+      let ours = self.blocks.entry(origin).or_insert([0; WORDS]);
+      for (a, b) in ours.iter_mut().zip(theirs.iter()) {
+        self.len += (*b & !*a).count_ones() as usize;
+        *a |= b;
+      }
+    }
+  }
+
+  /// Removes every point from this set that isn't also in `other`.
+  pub fn intersect_with(&mut self, other: &PointSet) {
+    let mut len = 0;
+    self.blocks.retain(|origin, ours| {
+      let theirs = match other.blocks.get(origin) {
+        Some(block) => block,
+        None => return false,
+      };
+      for (a, b) in ours.iter_mut().zip(theirs.iter()) {
+        *a &= b;
+        len += a.count_ones() as usize;
+      }
+      ours.iter().any(|&w| w != 0)
+    });
+    self.len = len;
+  }
+
+  /// Returns a new set with every point that is in either `self` or `other`.
+  pub fn union(&self, other: &PointSet) -> PointSet {
+    let mut set = self.clone();
+    set.union_with(other);
+    set
+  }
+
+  /// Returns a new set with every point that is in both `self` and `other`.
+  pub fn intersection(&self, other: &PointSet) -> PointSet {
+    let mut set = self.clone();
+    set.intersect_with(other);
+    set
+  }
+}
+
+/// Returns an iterator over the points whose bits are set in `block`, which
+/// has its upper-left corner at `origin`.
+fn block_points(
+  origin: Point,
+  block: &Block,
+) -> impl Iterator<Item = Point> + '_ {
+  block.iter().enumerate().flat_map(move |(i, &word)| {
+    let mut word = word;
+    std::iter::from_fn(move || {
+      if word == 0 {
+        return None;
+      }
+      let bit = i * 64 + word.trailing_zeros() as usize;
+      word &= word - 1;
+      let bit = bit as i64;
+      Some(origin + Point::new(bit % WIDTH, bit / WIDTH))
+    })
+  })
+}
+
+impl Extend<Point> for PointSet {
+  fn extend<I: IntoIterator<Item = Point>>(&mut self, iter: I) {
+    for p in iter {
+      self.insert(p);
+    }
+  }
+}
+
+impl FromIterator<Point> for PointSet {
+  fn from_iter<I: IntoIterator<Item = Point>>(iter: I) -> Self {
+    let mut set = PointSet::new();
+    set.extend(iter);
+    set
+  }
+}
+
+impl Encode for PointSet {
+  /// Writes the origin and bits of each block, sorted so that the same set
+  /// always saves the same way.
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    let mut origins = self.blocks.keys().collect::<Vec<_>>();
+    origins.sort_by_key(|p| (p.y(), p.x()));
+    origins.len().encode(w)?;
+    for origin in origins {
+      origin.encode(w)?;
+      for word in &self.blocks[origin] {
+        word.encode(w)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Decode for PointSet {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let mut set = PointSet::new();
+    for _ in 0..usize::decode(r)? {
+      let origin = Point::decode(r)?;
+      if locate(origin).0 != origin {
+        return Err(save::invalid(format!(
+          "block origin {:?} is not a multiple of {}",
+          origin, WIDTH
+        )));
+      }
+
+      let mut block = [0; WORDS];
+      for word in &mut block {
+        *word = u64::decode(r)?;
+      }
+      if block.iter().all(|&w| w == 0) {
+        return Err(save::invalid(format!("empty block at {:?}", origin)));
+      }
+
+      set.len += block.iter().map(|w| w.count_ones() as usize).sum::<usize>();
+      if set.blocks.insert(origin, block).is_some() {
+        return Err(save::invalid(format!("duplicate block at {:?}", origin)));
+      }
+    }
+    Ok(set)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::collections::HashSet;
+
+  use rand::Rng as _;
+
+  use crate::rng::Stream;
+
+  /// A region spanning several blocks on either side of the origin.
+  fn region() -> Rect {
+    Rect::new(Point::new(-70, -45), Point::new(70, 45))
+  }
+
+  /// Returns `n` random points of `region()`, with plenty of repeats.
+  fn random_points(seed: u64, n: usize) -> Vec<Point> {
+    let mut rng = Stream::new(seed, 0);
+    let [x1, y1] = region().upper_left().coords();
+    let [x2, y2] = region().lower_right().coords();
+    (0..n)
+      .map(|_| Point::new(rng.gen_range(x1..x2), rng.gen_range(y1..y2)))
+      .collect()
+  }
+
+  /// Checks that `set` has exactly the points in `reference`.
+  fn assert_same(set: &PointSet, reference: &HashSet<Point>) {
+    assert_eq!(set.len(), reference.len());
+    assert_eq!(set.is_empty(), reference.is_empty());
+    assert_eq!(set.iter().count(), reference.len());
+    assert_eq!(&set.iter().collect::<HashSet<_>>(), reference);
+    for p in region().points() {
+      assert_eq!(set.contains(p), reference.contains(&p), "{:?}", p);
+    }
+  }
+
+  #[test]
+  fn insert_and_remove() {
+    let mut set = PointSet::new();
+    let mut reference = HashSet::new();
+    for (i, p) in random_points(1, 3000).into_iter().enumerate() {
+      if i % 3 == 0 {
+        assert_eq!(set.remove(p), reference.remove(&p), "{:?}", p);
+      } else {
+        assert_eq!(set.insert(p), reference.insert(p), "{:?}", p);
+      }
+      assert_eq!(set.len(), reference.len());
+    }
+    assert_same(&set, &reference);
+
+    // Removing points that were never there, including from blocks that don't
+    // exist at all, changes nothing.
+    for &p in &[Point::new(1000, 1000), Point::new(-1000, 3)] {
+      assert!(!set.remove(p));
+    }
+    for p in region().points() {
+      if !reference.contains(&p) {
+        assert!(!set.remove(p));
+      }
+    }
+    assert_same(&set, &reference);
+
+    // Removing everything leaves no empty blocks behind.
+    for p in reference.drain() {
+      assert!(set.remove(p));
+    }
+    assert_same(&set, &reference);
+    assert_eq!(set, PointSet::new());
+  }
+
+  #[test]
+  fn union_and_intersection() {
+    for seed in 0..10 {
+      let a = random_points(seed * 2, 500);
+      let b = random_points(seed * 2 + 1, 500);
+      let (a_set, b_set) = (
+        a.iter().copied().collect::<PointSet>(),
+        b.iter().copied().collect::<PointSet>(),
+      );
+      let (a_ref, b_ref) = (
+        a.into_iter().collect::<HashSet<_>>(),
+        b.into_iter().collect::<HashSet<_>>(),
+      );
+
+      let union = a_set.union(&b_set);
+      assert_same(&union, &a_ref.union(&b_ref).copied().collect());
+      let intersection = a_set.intersection(&b_set);
+      assert_same(
+        &intersection,
+        &a_ref.intersection(&b_ref).copied().collect(),
+      );
+
+      let mut set = a_set.clone();
+      set.union_with(&a_set);
+      assert_same(&set, &a_ref);
+      set.intersect_with(&a_set);
+      assert_same(&set, &a_ref);
+    }
+  }
+
+  #[test]
+  fn intersection_drops_emptied_blocks() {
+    let points = |ps: &[(i64, i64)]| {
+      ps.iter()
+        .map(|&(x, y)| Point::new(x, y))
+        .collect::<PointSet>()
+    };
+    // The two sets share a block around the origin and one further out, but
+    // only have points in common in the first.
+    let mut a = points(&[(0, 0), (5, 5), (40, -3), (-1, -1)]);
+    let b = points(&[(5, 5), (41, -3), (-2, -1)]);
+    a.intersect_with(&b);
+    assert_eq!(a, points(&[(5, 5)]));
+    assert_eq!(a.len(), 1);
+
+    a.intersect_with(&points(&[(6, 5)]));
+    assert!(a.is_empty());
+    assert_eq!(a, PointSet::new());
+  }
+
+  #[test]
+  fn intersects() {
+    let set = [(-33, -1), (31, 31), (32, 32)]
+      .iter()
+      .map(|&(x, y)| Point::new(x, y))
+      .collect::<PointSet>();
+    let rect =
+      |x1, y1, x2, y2| Rect::new(Point::new(x1, y1), Point::new(x2, y2));
+
+    assert!(set.intersects(rect(-40, -40, 40, 40)));
+    assert!(set.intersects(rect(-33, -1, -32, 0)));
+    assert!(set.intersects(rect(30, 30, 32, 32)));
+    assert!(set.intersects(rect(32, 32, 100, 100)));
+    assert!(!set.intersects(rect(-32, -1, 31, 31)));
+    assert!(!set.intersects(rect(-34, 0, -32, 5)));
+    assert!(!set.intersects(rect(0, 0, 0, 0)));
+    assert!(!set.intersects(rect(31, -40, 31, 40)));
+    assert!(!set.intersects(rect(-40, 32, 40, 32)));
+    assert!(!PointSet::new().intersects(rect(-40, -40, 40, 40)));
+  }
+
+  #[test]
+  fn iter_in() {
+    let points = random_points(7, 2000);
+    let set = points.iter().copied().collect::<PointSet>();
+    let reference = points.into_iter().collect::<HashSet<_>>();
+
+    let mut rng = Stream::new(8, 0);
+    let [x1, y1] = region().upper_left().coords();
+    let [x2, y2] = region().lower_right().coords();
+    for _ in 0..200 {
+      let a = Point::new(rng.gen_range(x1..x2), rng.gen_range(y1..y2));
+      let b = Point::new(rng.gen_range(x1..x2), rng.gen_range(y1..y2));
+      let rect = Rect::new(a, b);
+
+      let found = set.iter_in(rect).collect::<Vec<_>>();
+      let expected = reference
+        .iter()
+        .copied()
+        .filter(|&p| rect.contains(p))
+        .collect::<HashSet<_>>();
+      assert_eq!(found.len(), expected.len(), "{:?}", rect);
+      assert_eq!(found.into_iter().collect::<HashSet<_>>(), expected);
+      assert_eq!(set.intersects(rect), !expected.is_empty());
+    }
+  }
+
+  fn encode(set: &PointSet) -> Vec<u8> {
+    let mut buf = Vec::new();
+    set.encode(&mut buf).unwrap();
+    buf
+  }
+
+  #[test]
+  fn round_trip() {
+    let set = [(0, 0), (-1, -1), (-32, -64), (31, 32), (100, -7)]
+      .iter()
+      .map(|&(x, y)| Point::new(x, y))
+      .collect::<PointSet>();
+    let decoded = PointSet::decode(&mut encode(&set).as_slice()).unwrap();
+    assert_eq!(decoded, set);
+    assert_eq!(decoded.len(), 5);
+  }
+
+  #[test]
+  fn encoding_is_deterministic() {
+    let points = (0..200).map(|i| Point::new(i * 7 - 500, i * 13 - 900));
+    let a = points.clone().collect::<PointSet>();
+    let b = points.rev().collect::<PointSet>();
+    assert_eq!(encode(&a), encode(&b));
+  }
+
+  #[test]
+  fn bad_blocks_are_rejected() {
+    let set = Some(Point::new(-32, -64)).into_iter().collect::<PointSet>();
+
+    let mut misaligned = encode(&set);
+    misaligned[8..16].copy_from_slice(&(-31i64).to_le_bytes());
+    assert!(PointSet::decode(&mut misaligned.as_slice()).is_err());
+
+    let mut empty = encode(&set);
+    for b in &mut empty[24..] {
+      *b = 0;
+    }
+    assert!(PointSet::decode(&mut empty.as_slice()).is_err());
+  }
+}
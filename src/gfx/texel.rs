@@ -4,8 +4,16 @@
 //! *quite* like cells, because they carry a little bit more information. See
 //! the [`Texel`] type for more info.
 
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
 pub use palette::named as colors;
 
+use crate::save;
+use crate::save::Decode;
+use crate::save::Encode;
+
 /// An RGB value used by a [`Texel`].
 pub type Rgb = palette::Srgb<u8>;
 
@@ -177,6 +185,33 @@ impl Texel {
     self
   }
 
+  /// Returns a copy of this texel as it looks under light of the given color,
+  /// which scales each channel of its colors.
+  ///
+  /// A foreground that is reset to the terminal's default is tinted as if it
+  /// were white; a reset background is left alone, as if it were black.
+  pub fn lit(self, light: Rgb) -> Self {
+    let tint = |c: Rgb| {
+      let scale = |x: u8, y: u8| (x as u16 * y as u16 / 255) as u8;
+      Rgb::new(
+        scale(c.red, light.red),
+        scale(c.green, light.green),
+        scale(c.blue, light.blue),
+      )
+    };
+
+    let mut tx = self;
+    match self.fg() {
+      Color::Rgb(c) => tx = tx.with_fg(tint(c)),
+      Color::Reset => tx = tx.with_fg(tint(colors::WHITE)),
+      Color::Inherit => {}
+    }
+    if let Color::Rgb(c) = self.bg() {
+      tx = tx.with_bg(tint(c));
+    }
+    tx
+  }
+
   /// Layers `other` over this `Texel`, following any relevant inheritance
   /// rules.
   #[inline]
@@ -196,3 +231,36 @@ impl Texel {
     self
   }
 }
+
+impl Encode for Texel {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.glyph.map(u32::from).encode(w)?;
+    w.write_all(&[self.fg.red, self.fg.green, self.fg.blue])?;
+    w.write_all(&[self.bg.red, self.bg.green, self.bg.blue])?;
+    self.meta.bits().encode(w)
+  }
+}
+
+impl Decode for Texel {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let glyph = match Option::<u32>::decode(r)? {
+      Some(c) => Some(
+        std::char::from_u32(c)
+          .ok_or_else(|| save::invalid(format!("invalid glyph {:#x}", c)))?,
+      ),
+      None => None,
+    };
+
+    let mut rgb = [0; 6];
+    r.read_exact(&mut rgb)?;
+    let meta = u16::decode(r)?;
+    Ok(Self {
+      glyph,
+      fg: Rgb::new(rgb[0], rgb[1], rgb[2]),
+      bg: Rgb::new(rgb[3], rgb[4], rgb[5]),
+      meta: Meta::from_bits(meta).ok_or_else(|| {
+        save::invalid(format!("invalid texel flags {:#x}", meta))
+      })?,
+    })
+  }
+}
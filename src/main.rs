@@ -2,8 +2,19 @@
 
 #![deny(unused)]
 #![deny(warnings)]
-
-use std::collections::HashSet;
+// Legion systems take all of their resources as arguments, and most of our
+// types have a meaningful `new()` that we don't want to hide behind `Default`.
+#![allow(clippy::too_many_arguments)]
+#![allow(clippy::new_without_default)]
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
 use std::time::Duration;
 
 pub mod actor;
@@ -11,13 +22,20 @@ pub mod geo;
 pub mod gfx;
 pub mod input;
 pub mod map;
+pub mod rng;
+pub mod save;
 pub mod timing;
 pub mod ui;
 
+/// Where a run is saved to when quitting with F2.
+const SAVE_PATH: &str = "crawl.sav";
+
 fn main() {
   use crate::geo::*;
   use crate::gfx::texel::*;
   use crate::map::*;
+  use crate::save::Decode;
+  use crate::save::Encode;
   use crate::timing::*;
   use crate::ui::widget::*;
 
@@ -27,43 +45,102 @@ fn main() {
   use legion::Resources;
   use legion::Schedule;
   use legion::World;
+  use rand::RngCore as _;
+
+  // A seed may be passed to reproduce a previous run. With `--endless`, the
+  // player explores a single cave floor that goes on forever instead of the
+  // usual dungeon. With `--pick`, the player starts out with a pick, which lets
+  // them dig through walls. With `--data`, tiles and prefabs are loaded from
+  // the `tiles.txt` and `prefabs.txt` files in `DIR`, rather than the ones
+  // built into the game.
+  const USAGE: &str = "usage: crawl [--endless] [--pick] [--data DIR] [seed]";
+  fn bad_args(message: &str) -> ! {
+    eprintln!("crawl: {}\n{}", message, USAGE);
+    std::process::exit(2)
+  }
 
-  let mut floor = Floor::new();
-  floor.rooms_and_corridors(
-    50,
-    Rect::with_dims(200, 200).centered_on(Point::zero()),
-    Point::new(10, 10),
-    Point::new(30, 30),
-  );
-  let rooms = floor.rooms();
-
-  let mut world = World::default();
-  world.push((
+  let mut endless = false;
+  let mut pick = false;
+  let mut data = None;
+  let mut seed = None;
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--endless" => endless = true,
+      "--pick" => pick = true,
+      "--data" => match args.next() {
+        Some(dir) => data = Some(PathBuf::from(dir)),
+        None => bad_args("--data needs a path"),
+      },
+      _ if arg.starts_with('-') => {
+        bad_args(&format!("unknown option `{}`", arg))
+      }
+      _ if seed.is_some() => bad_args("only one seed may be given"),
+      _ => match arg.parse() {
+        Ok(s) => seed = Some(s),
+        Err(_) => bad_args(&format!("seed `{}` is not a u64", arg)),
+      },
+    }
+  }
+  let mut rng = match seed {
+    Some(seed) => rng::Rng::new(seed),
+    None => rng::Rng::from_entropy(),
+  };
+
+  let (tiles, prefabs) = match data {
+    Some(dir) => (
+      Tiles::load(dir.join("tiles.txt"))
+        .unwrap_or_else(|e| panic!("failed to load tiles.txt: {}", e)),
+      Prefabs::load(dir.join("prefabs.txt"))
+        .unwrap_or_else(|e| panic!("failed to load prefabs.txt: {}", e)),
+    ),
+    None => (Tiles::builtin(), Prefabs::builtin()),
+  };
+  let mut streamer = None;
+  let (floor, mut world, start) = if endless {
+    let generator = EndlessCaves::new(rng.stream("map").next_u64(), 0.45, 4);
+    let mut s = stream::Streamer::new(Box::new(generator))
+      .expect("failed to create chunk swap directory");
+
+    let mut floor = Floor::new();
+    let near_origin = Rect::with_dims(64, 64).centered_on(Point::zero());
+    s.update(&mut floor, near_origin);
+    let start = near_origin
+      .points()
+      .filter(|&p| tiles.get(floor.tile(p)).is_walkable())
+      .min_by_key(|&p| p.manhattan())
+      .expect("no open ground near the origin");
+
+    streamer = Some(s);
+    (floor, World::default(), start)
+  } else {
+    let (floor, world) =
+      Dungeon::generate(0, rng.stream("map"), &tiles, &prefabs);
+    let start = floor.rooms()[0].center();
+    (floor, world, start)
+  };
+
+  let player = world.push((
     actor::player::Player,
     actor::base::HasCamera,
-    actor::base::Position(rooms[0].center()),
+    actor::base::Position(start),
     actor::base::Oriented(Dir::S),
     actor::base::Tangible,
-    actor::ai::Fov {
-      range: Point::new(20, 10),
-      visible: HashSet::new(),
-      seen: HashSet::new(),
-    },
+    actor::ai::Fov::new(Point::new(20, 10), &fov::Milazzo).with_memory(),
     actor::base::Sprite(Texel::new('@')),
+    actor::base::Health::new(200),
+    actor::base::MoveDelay(0),
+    actor::base::Gold(0),
+    actor::light::Light {
+      range: Point::new(8, 4),
+      color: colors::NAVAJOWHITE,
+    },
   ));
-
-  for room in &rooms[1..] {
-    world.push((
-      actor::base::Position(room.center()),
-      actor::base::Tangible,
-      actor::ai::Fov {
-        range: Point::new(20, 10),
-        visible: HashSet::new(),
-        seen: HashSet::new(),
-      },
-      actor::base::Sprite(Texel::new('K')),
-      actor::ai::Pathfind::new(vec![Box::new(actor::ai::Chase::new()), Box::new(actor::ai::Wander)]),
-    ));
+  if pick {
+    world
+      .entry(player)
+      .unwrap()
+      .add_component(actor::base::Digger);
   }
 
   #[allow(unused)]
@@ -74,6 +151,26 @@ fn main() {
     gold: u32,
   }
 
+  impl Encode for WState {
+    fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+      self.health.encode(w)?;
+      self.pos.encode(w)?;
+      self.dir.encode(w)?;
+      self.gold.encode(w)
+    }
+  }
+
+  impl Decode for WState {
+    fn decode(r: &mut dyn Read) -> io::Result<Self> {
+      Ok(WState {
+        health: Decode::decode(r)?,
+        pos: Decode::decode(r)?,
+        dir: Decode::decode(r)?,
+        gold: Decode::decode(r)?,
+      })
+    }
+  }
+
   enum WType {
     Health,
     Magic,
@@ -161,15 +258,113 @@ fn main() {
   bar.push(WType::Spacer(None), 40);
   bar.push(WType::Gold, 50);
 
+  /// Resource: Something that went wrong that the player should know about,
+  /// which is shown along with the debug information.
+  struct Warning(Option<String>);
+
   let mut resources = Resources::default();
   resources.insert(gfx::Curses::init());
   resources.insert(FrameTimer::new());
   resources.insert(SystemTimer::new());
   resources.insert(floor);
+  resources.insert(Dungeon::new());
+  resources.insert(tiles);
+  resources.insert(prefabs);
+  resources.insert(rng);
   resources.insert(input::UserInput::new());
   resources.insert(actor::ai::TurnMode::Waiting);
+  resources.insert(actor::light::LightMap::new());
   resources.insert(gfx::Renderer::new());
   resources.insert(bar);
+  resources.insert(Warning(None));
+  if let Some(streamer) = streamer {
+    resources.insert(streamer);
+  }
+
+  // Everything that needs to be saved in order to resume a run.
+  let mut registry = save::Registry::new();
+  registry
+    .component_with(
+      "has_camera",
+      |_: &actor::base::HasCamera, _| Ok(()),
+      |_| Ok(actor::base::HasCamera),
+    )
+    .component::<actor::base::Position>("position")
+    .component::<actor::base::Oriented>("oriented")
+    .component_with(
+      "tangible",
+      |_: &actor::base::Tangible, _| Ok(()),
+      |_| Ok(actor::base::Tangible),
+    )
+    .component::<actor::base::Sprite>("sprite")
+    .component_with(
+      "swimmer",
+      |_: &actor::base::Swimmer, _| Ok(()),
+      |_| Ok(actor::base::Swimmer),
+    )
+    .component_with(
+      "digger",
+      |_: &actor::base::Digger, _| Ok(()),
+      |_| Ok(actor::base::Digger),
+    )
+    .component_with(
+      "trapwise",
+      |_: &actor::base::Trapwise, _| Ok(()),
+      |_| Ok(actor::base::Trapwise),
+    )
+    .component::<actor::base::Gold>("gold")
+    .component::<actor::base::MoveDelay>("move_delay")
+    .component::<actor::base::Health>("health")
+    .component::<actor::light::Light>("light")
+    .component::<actor::ai::Fov>("fov")
+    .component_with(
+      "pathfind",
+      actor::ai::Pathfind::save,
+      actor::ai::Pathfind::load,
+    )
+    .component_with(
+      "player",
+      |_: &actor::player::Player, _| Ok(()),
+      |_| Ok(actor::player::Player),
+    )
+    .resource::<rng::Rng>("rng")
+    .resource::<actor::ai::TurnMode>("turn_mode")
+    .resource_with(
+      "widgets",
+      |bar: &WidgetBar<WType>, w| bar.state().encode(w),
+      |bar, r| {
+        *bar.state_mut() = WState::decode(r)?;
+        bar.mark_dirty();
+        Ok(())
+      },
+    );
+
+  // If there's a saved run, pick it back up. The save is removed once loaded,
+  // so that the same run can't be resumed twice. A save that can't be loaded,
+  // such as one from an older version, is left where it is, and a new run is
+  // started instead.
+  //
+  // Endless floors can't be saved, since their generators aren't, so they
+  // leave saves alone altogether.
+  let can_save = !endless;
+  if can_save {
+    if let Ok(file) = File::open(SAVE_PATH) {
+      match Dungeon::load(
+        &mut world,
+        &mut resources,
+        &registry,
+        &mut BufReader::new(file),
+      ) {
+        Ok(()) => {
+          fs::remove_file(SAVE_PATH).expect("failed to remove saved game")
+        }
+        Err(e) => {
+          resources.get_mut::<Warning>().unwrap().0 =
+            Some(format!("couldn't load {}: {}", SAVE_PATH, e))
+        }
+      }
+    }
+  }
 
   #[legion::system]
   fn quit(
@@ -184,15 +379,25 @@ fn main() {
   #[legion::system(for_each)]
   #[read_component(actor::base::Position)]
   #[read_component(actor::base::Oriented)]
+  #[read_component(actor::base::Health)]
+  #[read_component(actor::base::Gold)]
   #[filter(legion::component::<actor::player::Player>())]
   fn update_widgets(
     pos: &actor::base::Position,
     dir: &actor::base::Oriented,
+    health: &actor::base::Health,
+    gold: &actor::base::Gold,
     #[resource] timer: &SystemTimer,
     #[resource] widget_bar: &mut WidgetBar<WType>,
   ) {
     let _t = timer.start("update_widgets()");
 
+    let state = widget_bar.state_mut();
+    if state.health != health.current {
+      state.health = health.current;
+      widget_bar.mark_dirty();
+    }
+
     let state = widget_bar.state_mut();
     if state.pos != pos.0 {
       state.pos = pos.0;
@@ -204,6 +409,12 @@ fn main() {
       state.dir = dir.0;
       widget_bar.mark_dirty();
     }
+
+    let state = widget_bar.state_mut();
+    if state.gold != gold.0 {
+      state.gold = gold.0;
+      widget_bar.mark_dirty();
+    }
   }
 
   #[legion::system]
@@ -217,9 +428,13 @@ fn main() {
     #[resource] frame_timer: &mut FrameTimer,
     #[resource] timer: &SystemTimer,
     #[resource] floor: &Floor,
+    #[resource] tiles: &Tiles,
+    #[resource] lights: &actor::light::LightMap,
+    #[resource] rng: &rng::Rng,
     #[resource] window: &gfx::Curses,
     #[resource] renderer: &mut gfx::Renderer,
     #[resource] widget_bar: &mut WidgetBar<WType>,
+    #[resource] warning: &Warning,
   ) {
     use crate::actor::base::*;
     use crate::actor::player::Player;
@@ -235,37 +450,52 @@ fn main() {
     let mut scene = gfx::Scene::new(camera, true);
     let viewport = scene.viewport();
 
+    // The map is drawn the way the player remembers it: tiles they can see are
+    // drawn as they are, tinted by the light falling on them, tiles they can't
+    // are drawn greyed out as they were when last seen, and everything else is
+    // left blank.
+    let fovs = <&Fov>::query()
+      .filter(legion::component::<Player>())
+      .iter(world)
+      .collect::<Vec<_>>();
+    let mut visible = PointSet::new();
+    for fov in &fovs {
+      visible.union_with(&fov.visible);
+    }
+
     let mut map_layer = scene.image_layer(0);
-    for (_, chunk) in floor.chunks_in(viewport) {
-      map_layer.push(chunk.image());
+    let mut map = RectVec::new(viewport, Texel::new(' '));
+    for memory in fovs.iter().rev().filter_map(|f| f.memory.as_ref()) {
+      for (p, tile) in memory.iter_in(viewport) {
+        if let Some(tx) = map.get_mut(p) {
+          *tx = tiles.get(tile).texel.with_fg(colors::GRAY);
+        }
+      }
     }
+    for p in visible.iter_in(viewport) {
+      if let Some(tx) = map.get_mut(p) {
+        *tx = tiles.get(floor.tile(p)).texel;
+        if let Some(light) = lights.get(p) {
+          *tx = tx.lit(light);
+        }
+      }
+    }
+    map_layer.push(map);
     map_layer.finish();
 
+    // Actors can move around, so they are only drawn while in sight.
     let mut sprite_layer = scene.image_layer(1);
     for (pos, Sprite(tx)) in <(&Position, &Sprite)>::query().iter(world) {
-      sprite_layer
-        .push(RectVec::new(Rect::with_dims(1, 1).centered_on(pos.0), *tx));
-    }
-
-    sprite_layer.finish();
-
-    let mut fov_layer = scene.image_layer(2);
-    let mut fov_mask = RectVec::new(viewport, Texel::new(' '));
-    for fov in <&Fov>::query()
-      .filter(legion::component::<Player>())
-      .iter(world)
-    {
-      for p in &fov.seen {
-        fov_mask
-          .get_mut(*p)
-          .map(|t| *t = Texel::empty().with_fg(colors::GRAY));
-      }
-      for p in &fov.visible {
-        fov_mask.get_mut(*p).map(|t| *t = Texel::empty());
+      if visible.contains(pos.0) {
+        let tx = match lights.get(pos.0) {
+          Some(light) => tx.lit(light),
+          None => *tx,
+        };
+        sprite_layer
+          .push(RectVec::new(Rect::with_dims(1, 1).centered_on(pos.0), tx));
       }
     }
-    fov_layer.push(fov_mask);
-    fov_layer.finish();
+    sprite_layer.finish();
 
     let mut ui_layer = scene.image_layer(3);
     let widgets = widget_bar.draw(80);
@@ -281,6 +511,10 @@ fn main() {
     let fps = frame_timer.measure_fps(Duration::from_millis(500));
     let count = frame_timer.frame_count();
     scene.debug(format!("fps: {:.2}, count: {}", fps, count));
+    scene.debug(format!("seed: {}", rng.seed()));
+    if let Some(warning) = &warning.0 {
+      scene.debug(format!("warning: {}", warning));
+    }
 
     scene.debug("Timings:".into());
     for (system, duration) in timer.measure(Duration::from_millis(500)) {
@@ -297,14 +531,24 @@ fn main() {
     frame_timer.end_frame(60);
   }
 
-  let mut schedule = Schedule::builder()
+  let mut schedule = Schedule::builder();
+  schedule
     .add_system(input::start_frame_system())
     .add_system(quit_system())
     .add_system(actor::player::player_movement_system())
+    .add_system(actor::player::search_system())
+    .add_system(actor::player::take_stairs_system())
+    .add_system(actor::player::pick_up_gold_system())
     .add_system(update_widgets_system())
-    .flush()
+    .flush();
+  if endless {
+    schedule.add_system(stream::stream_chunks_system());
+  }
+  let mut schedule = schedule
+    .add_system(actor::light::update_lights_system())
     .add_system(actor::ai::update_fov_system())
     .add_system(actor::ai::pathfind_system())
+    .add_system(actor::hazard::hazards_system())
     .flush()
     .add_system(actor::ai::end_turn_system())
     .add_system(render_system())
@@ -312,5 +556,39 @@ fn main() {
 
   loop {
     schedule.execute(&mut world, &mut resources);
+    Dungeon::travel(&mut world, &mut resources);
+
+    // Streaming carries on as best it can when swapping chunks fails, but
+    // tunnels dug in a chunk that couldn't be read back in are gone, so the
+    // player should hear about it.
+    let stream_error = resources
+      .get::<stream::Streamer>()
+      .and_then(|s| Some(format!("chunk streaming failed: {}", s.error()?)));
+    if stream_error.is_some() {
+      resources.get_mut::<Warning>().unwrap().0 = stream_error;
+    }
+
+    // The run is over once the player dies.
+    let is_dead = <&actor::base::Health>::query()
+      .filter(legion::component::<actor::player::Player>())
+      .iter(&world)
+      .any(|h| h.current == 0);
+    if is_dead {
+      resources.get_mut::<gfx::Curses>().unwrap().die(0);
+    }
+
+    // F2 saves the run and quits.
+    let input = resources.get::<input::UserInput>().unwrap();
+    let should_save = input.has_key(input::KeyCode::F(2));
+    drop(input);
+    if should_save && can_save {
+      let mut file = BufWriter::new(
+        File::create(SAVE_PATH).expect("failed to create save file"),
+      );
+      Dungeon::save(&world, &resources, &registry, &mut file)
+        .and_then(|_| file.flush())
+        .expect("failed to save game");
+      resources.get_mut::<gfx::Curses>().unwrap().die(0);
+    }
   }
 }
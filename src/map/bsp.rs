@@ -0,0 +1,129 @@
+//! Binary space partitioning generator.
+
+use std::ops::Range;
+
+use rand::Rng;
+
+use crate::geo::Point;
+use crate::geo::Rect;
+use crate::map::Floor;
+use crate::map::Prefabs;
+use crate::map::Room;
+use crate::map::RoomKind;
+use crate::map::PREFAB_CHANCE;
+
+impl Floor {
+  /// Generates rooms by binary space partitioning.
+  ///
+  /// `bounds` is recursively split in two along a random axis, until splitting
+  /// again would produce a piece smaller than `min_leaf`. Each of the resulting
+  /// leaves receives a room at least `min_room` in size, and the two halves of
+  /// every split are joined by a corridor between their closest rooms, so every
+  /// room is reachable from every other. Some leaves receive one of `prefabs`
+  /// instead, if one fits.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `min_room` does not fit inside `min_leaf`, or if `bounds` is
+  /// smaller than `min_leaf`.
+  pub fn bsp(
+    &mut self,
+    rng: &mut impl Rng,
+    prefabs: &Prefabs,
+    bounds: Rect,
+    min_leaf: Point,
+    min_room: Point,
+  ) {
+    assert!(min_room.x() <= min_leaf.x() && min_room.y() <= min_leaf.y());
+    self.bsp_split(rng, prefabs, bounds, min_leaf, min_room);
+    self.place_doors();
+  }
+
+  /// Performs one step of BSP generation on `rect`, returning the range of
+  /// indices into `self.rooms` of all rooms generated within it.
+  fn bsp_split(
+    &mut self,
+    rng: &mut impl Rng,
+    prefabs: &Prefabs,
+    rect: Rect,
+    min_leaf: Point,
+    min_room: Point,
+  ) -> Range<usize> {
+    let (start, end) = rect.corners();
+    let can_split_x = rect.width() >= min_leaf.x() * 2;
+    let can_split_y = rect.height() >= min_leaf.y() * 2;
+
+    let split_x = match (can_split_x, can_split_y) {
+      (false, false) => {
+        // This is a leaf, so we place a room somewhere inside of it.
+        let prefab = match rng.gen_bool(PREFAB_CHANCE) {
+          true => prefabs.pick(rng, Point::new(rect.width(), rect.height())),
+          false => None,
+        };
+        let (w, h) = match prefab {
+          Some((prefab, transform)) => {
+            let dims = prefab.dims(transform);
+            (dims.x(), dims.y())
+          }
+          None => (
+            rng.gen_range(min_room.x()..=rect.width()),
+            rng.gen_range(min_room.y()..=rect.height()),
+          ),
+        };
+        let x = rng.gen_range(start.x()..=end.x() - w);
+        let y = rng.gen_range(start.y()..=end.y() - h);
+
+        let room = Rect::with_dims(w, h) + Point::new(x, y);
+        match prefab {
+          Some((prefab, transform)) => {
+            self.add_prefab(prefab, transform, room.upper_left());
+          }
+          None => self.add_room(room),
+        }
+        let kind = match prefab {
+          Some((prefab, _)) => prefab.kind(),
+          None => RoomKind::Plain,
+        };
+        self.rooms.push(Room::new(room, kind));
+        return self.rooms.len() - 1..self.rooms.len();
+      }
+      (true, false) => true,
+      (false, true) => false,
+      // Prefer splitting along the long side, to avoid producing slivers.
+      (true, true) if rect.width() * 4 > rect.height() * 5 => true,
+      (true, true) if rect.height() * 4 > rect.width() * 5 => false,
+      (true, true) => rng.gen(),
+    };
+
+    let (a, b) = if split_x {
+      let x = rng.gen_range(start.x() + min_leaf.x()..=end.x() - min_leaf.x());
+      (
+        Rect::new(start, Point::new(x, end.y())),
+        Rect::new(Point::new(x, start.y()), end),
+      )
+    } else {
+      let y = rng.gen_range(start.y() + min_leaf.y()..=end.y() - min_leaf.y());
+      (
+        Rect::new(start, Point::new(end.x(), y)),
+        Rect::new(Point::new(start.x(), y), end),
+      )
+    };
+
+    let left = self.bsp_split(rng, prefabs, a, min_leaf, min_room);
+    let right = self.bsp_split(rng, prefabs, b, min_leaf, min_room);
+
+    // Connect the closest pair of rooms across the split; this keeps corridors
+    // short, so they cut through as few other rooms as possible.
+    let rooms = &self.rooms;
+    let (from, to) = left
+      .clone()
+      .flat_map(|i| right.clone().map(move |j| (i, j)))
+      .map(|(i, j)| (rooms[i].center(), rooms[j].center()))
+      .min_by_key(|&(from, to)| (from - to).manhattan())
+      .unwrap();
+    let zones = self.zones();
+    self.add_corridor(&zones, from, to);
+
+    left.start..right.end
+  }
+}
@@ -0,0 +1,361 @@
+//! Cellular automata cave generator.
+
+use std::collections::VecDeque;
+
+use rand::distributions::Bernoulli;
+use rand::distributions::Distribution as _;
+use rand::Rng;
+
+use crate::geo::Dir;
+use crate::geo::Point;
+use crate::geo::Rect;
+use crate::geo::RectVec;
+use crate::map::stream::ChunkGenerator;
+use crate::map::Chunk;
+use crate::map::Floor;
+use crate::map::Room;
+use crate::map::RoomKind;
+use crate::map::Tile;
+use crate::rng;
+
+/// The smallest width and height the interior of a cave area may have.
+const MIN_AREA: i64 = 3;
+
+impl Floor {
+  /// Generates an organic cave level using cellular automata.
+  ///
+  /// The interior of `bounds` is first seeded with walls, each point being a
+  /// wall with probability `fill`; then, the automaton is run `iterations`
+  /// times, which turns any point with five or more walls in its 3x3
+  /// neighborhood into a wall, and every other point into ground. A good
+  /// starting point is a `fill` of 0.45 with four or five iterations.
+  ///
+  /// Afterwards, every pocket that is not reachable from the largest open area
+  /// is filled in, and the rock bordering open ground becomes [`Tile::Wall`].
+  ///
+  /// Because caves have no rectangular rooms, this function also carves up the
+  /// open space into "cave areas", which are reported by [`Floor::rooms()`].
+  /// Like rooms, only the interior of an area is guaranteed to be ground.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `fill` is not between zero and one.
+  pub fn caves(
+    &mut self,
+    rng: &mut impl Rng,
+    bounds: Rect,
+    fill: f64,
+    iterations: usize,
+  ) {
+    let is_wall = Bernoulli::new(fill).unwrap();
+    let mut walls = RectVec::new(bounds, true);
+    for (p, wall) in walls.points_mut() {
+      *wall = bounds.boundary_contains(p) || is_wall.sample(rng);
+    }
+
+    let mut scratch = walls.clone();
+    for _ in 0..iterations {
+      for (p, wall) in scratch.points_mut() {
+        *wall = step(&walls, p);
+      }
+      std::mem::swap(&mut walls, &mut scratch);
+    }
+
+    fill_pockets(&mut walls);
+
+    for (p, &wall) in walls.points() {
+      let tile = if !wall {
+        Tile::Ground
+      } else if Dir::all()
+        .iter()
+        .any(|&d| walls.get(p + d.to_point::<i64>()) == Some(&false))
+      {
+        Tile::Wall
+      } else {
+        continue;
+      };
+
+      let slot = self.chunk_mut(p).tile_mut(p);
+      if tile > *slot {
+        *slot = tile;
+      }
+    }
+
+    // Finally, greedily carve the open space into the largest rectangles we can
+    // find, which serve as this level's "rooms".
+    for area in open_areas(walls, MIN_AREA) {
+      let (start, end) = area.corners();
+      let rect = Rect::new(start - Point::new(1, 1), end + Point::new(1, 1));
+      self.rooms.push(Room::new(rect, RoomKind::Cavern));
+    }
+  }
+}
+
+/// Generates endless caves, one chunk at a time.
+///
+/// This runs the same automaton as [`Floor::caves()`], but seeds it by hashing
+/// each point's position, and runs it over each chunk plus a margin wide
+/// enough that every point comes out the same no matter which chunk it is
+/// computed for. Unlike [`Floor::caves()`], there is no way to fill in pockets
+/// that are cut off from the rest of the cave, nor to find cave areas.
+pub struct EndlessCaves {
+  seed: u64,
+  fill: f64,
+  iterations: usize,
+}
+
+impl EndlessCaves {
+  /// Creates a new `EndlessCaves`; see [`Floor::caves()`] for the meaning of
+  /// `fill` and `iterations`.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `fill` is not between zero and one.
+  pub fn new(seed: u64, fill: f64, iterations: usize) -> Self {
+    assert!((0.0..=1.0).contains(&fill), "fill must be a probability");
+    Self {
+      seed,
+      fill,
+      iterations,
+    }
+  }
+}
+
+impl EndlessCaves {
+  /// Computes the tiles of every point in `rect`.
+  ///
+  /// Every point comes out the same no matter what `rect` it is computed as
+  /// part of, which is what keeps the seams between chunks coherent.
+  fn tiles_in(&self, rect: Rect) -> RectVec<Tile> {
+    // Each iteration needs every neighbor of the points it computes, so we
+    // start out with a margin of one point per iteration, plus one more for
+    // finding the walls at the end.
+    let margin = self.iterations as i64 + 1;
+    let (start, end) = rect.corners();
+    let inflate = |by: i64| {
+      let by = Point::new(by, by);
+      Rect::new(start - by, end + by)
+    };
+
+    let threshold = (self.fill * u64::MAX as f64) as u64;
+    let mut walls = RectVec::new(inflate(margin), true);
+    for (p, wall) in walls.points_mut() {
+      *wall = rng::hash_point(self.seed, p) < threshold;
+    }
+
+    for i in 1..=self.iterations as i64 {
+      let mut next = RectVec::new(inflate(margin - i), true);
+      for (p, wall) in next.points_mut() {
+        *wall = step(&walls, p);
+      }
+      walls = next;
+    }
+
+    let mut tiles = RectVec::new(rect, Tile::Void);
+    for (p, tile) in tiles.points_mut() {
+      *tile = if !walls.get(p).unwrap() {
+        Tile::Ground
+      } else if Dir::all()
+        .iter()
+        .any(|&d| walls.get(p + d.to_point::<i64>()) == Some(&false))
+      {
+        Tile::Wall
+      } else {
+        Tile::Void
+      };
+    }
+    tiles
+  }
+}
+
+impl ChunkGenerator for EndlessCaves {
+  fn generate(&self, chunk: &mut Chunk) {
+    let tiles = self.tiles_in(chunk.rect());
+    for (p, &tile) in tiles.points() {
+      *chunk.tile_mut(p) = tile;
+    }
+  }
+}
+
+/// Runs one step of the cave automaton at `p`, returning whether it should be
+/// a wall.
+fn step(walls: &RectVec<bool>, p: Point) -> bool {
+  let neighbors = Dir::all()
+    .iter()
+    .filter(|&&d| *walls.get(p + d.to_point::<i64>()).unwrap_or(&true))
+    .count();
+  let is_wall = *walls.get(p).unwrap();
+  neighbors + is_wall as usize >= 5
+}
+
+/// Fills every open region of `walls` except for the largest one.
+fn fill_pockets(walls: &mut RectVec<bool>) {
+  let mut labels = RectVec::new(walls.dims(), None);
+  let mut sizes = Vec::new();
+  let mut queue = VecDeque::new();
+  for p in walls.dims().points() {
+    if *walls.get(p).unwrap() || labels.get(p).unwrap().is_some() {
+      continue;
+    }
+
+    let label = sizes.len();
+    let mut size = 0;
+    *labels.get_mut(p).unwrap() = Some(label);
+    queue.push_back(p);
+    while let Some(p) = queue.pop_front() {
+      size += 1;
+      for &d in &Dir::all() {
+        let n = p + d.to_point::<i64>();
+        if walls.get(n) != Some(&false) {
+          continue;
+        }
+        let slot = labels.get_mut(n).unwrap();
+        if slot.is_none() {
+          *slot = Some(label);
+          queue.push_back(n);
+        }
+      }
+    }
+    sizes.push(size);
+  }
+
+  let largest = (0..sizes.len()).max_by_key(|&i| sizes[i]);
+  for ((_, wall), (_, label)) in walls.points_mut().zip(labels.points()) {
+    if label.is_some() && *label != largest {
+      *wall = true;
+    }
+  }
+}
+
+/// Greedily carves the open space of `walls` into rectangles that are at least
+/// `min` points wide and tall, by repeatedly taking the largest one that is
+/// left, until there are none.
+///
+/// This uses the classic "largest rectangle in a histogram" algorithm on each
+/// row, where the histogram records how many open points lie directly above.
+/// Taking a rectangle only changes the histograms of the rows it covers, and
+/// of the rows below it up to the next wall in each of its columns, so only
+/// those are recomputed each time.
+fn open_areas(mut walls: RectVec<bool>, min: i64) -> Vec<Rect> {
+  let dims = walls.dims();
+  let (start, end) = dims.corners();
+  let rows = dims.height().max(0) as usize;
+  let mut heights = vec![Vec::new(); rows];
+  // The best rectangle whose bottom edge is in each row.
+  let mut best = vec![None; rows];
+
+  let mut areas = Vec::new();
+  let mut dirty = 0..rows;
+  loop {
+    for i in dirty.start..rows {
+      let y = start.y() + i as i64;
+      let row = (start.x()..end.x())
+        .enumerate()
+        .map(|(j, x)| match walls.get(Point::new(x, y)) {
+          Some(false) => i.checked_sub(1).map_or(0, |k| heights[k][j]) + 1,
+          _ => 0,
+        })
+        .collect::<Vec<_>>();
+      if i >= dirty.end && row == heights[i] {
+        break;
+      }
+      best[i] = largest_in_histogram(&row, start.x(), y, min);
+      heights[i] = row;
+    }
+
+    // Ties go to the topmost row.
+    let area = match best.iter().rev().flatten().max_by_key(|r| r.area()) {
+      Some(&area) => area,
+      None => return areas,
+    };
+    for p in area.points() {
+      *walls.get_mut(p).unwrap() = true;
+    }
+    areas.push(area);
+
+    let first = (area.upper_left().y() - start.y()) as usize;
+    let last = (area.lower_right().y() - start.y()) as usize;
+    dirty = first..last;
+  }
+}
+
+/// Finds the largest rectangle under the histogram `heights` that is at least
+/// `min` wide and tall, where the histogram's bars start at `x` and stand on
+/// the row `y`.
+///
+/// Every rectangle that can't be grown in any direction is visited along the
+/// way, and the largest rectangle that is big enough is always one of those,
+/// so it is enough to skip the ones that are too thin.
+fn largest_in_histogram(
+  heights: &[i64],
+  x: i64,
+  y: i64,
+  min: i64,
+) -> Option<Rect> {
+  let mut best: Option<Rect> = None;
+
+  // Each stack entry is the leftmost x at which a bar of the given height can
+  // start.
+  let mut stack = Vec::<(i64, i64)>::new();
+  for (i, x) in (x..=x + heights.len() as i64).enumerate() {
+    let h = heights.get(i).cloned().unwrap_or(0);
+    let mut left = x;
+    while let Some(&(x0, h0)) = stack.last() {
+      if h0 < h {
+        break;
+      }
+      stack.pop();
+      left = x0;
+
+      let rect = Rect::new(Point::new(x0, y + 1 - h0), Point::new(x, y + 1));
+      if rect.width() < min || rect.height() < min {
+        continue;
+      }
+      if best.map(|b| rect.area() > b.area()).unwrap_or(true) {
+        best = Some(rect);
+      }
+    }
+    stack.push((left, h));
+  }
+
+  best.filter(|r| !r.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::map::WIDTH;
+
+  #[test]
+  fn endless_chunks_line_up() {
+    let caves = EndlessCaves::new(7, 0.45, 4);
+    let width = WIDTH as i64;
+
+    // Compute a 3x3 block of chunks around the origin in one go, and check
+    // that each chunk generated on its own agrees with it, seams and all.
+    let corner = Point::new(-width, -width);
+    let region = Rect::new(corner, corner + Point::new(3 * width, 3 * width));
+    let expected = caves.tiles_in(region);
+    for y in -1..2 {
+      for x in -1..2 {
+        let mut chunk = Chunk::new(Point::new(x, y) * width);
+        caves.generate(&mut chunk);
+        for p in chunk.rect().points() {
+          assert_eq!(chunk.tile(p), expected.get(p).unwrap(), "{:?}", p);
+        }
+      }
+    }
+
+    // Ground is always walled off from the void, including across seams.
+    for (p, &tile) in expected.points() {
+      if tile != Tile::Ground {
+        continue;
+      }
+      for d in Dir::all().iter() {
+        let n = expected.get(p + d.to_point::<i64>());
+        assert_ne!(n, Some(&Tile::Void), "{:?}", p);
+      }
+    }
+  }
+}
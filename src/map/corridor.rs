@@ -0,0 +1,153 @@
+//! Corridor routing.
+
+use std::collections::HashMap;
+
+use crate::geo::graph;
+use crate::geo::Point;
+use crate::geo::Rect;
+use crate::map::Floor;
+use crate::map::Tile;
+
+/// The cost of stepping onto a tile that is already open.
+const OPEN_COST: f64 = 0.5;
+/// The cost of stepping onto empty space.
+const VOID_COST: f64 = 1.0;
+/// The cost of stepping onto a wall, which prefers existing doorways.
+const WALL_COST: f64 = 3.0;
+/// The extra cost of stepping through any room other than the ones being
+/// connected.
+const ROOM_PENALTY: f64 = 8.0;
+
+/// How far a corridor may stray outside the rectangle spanned by its ends.
+const MARGIN: i64 = 24;
+
+/// Where a point lies relative to the rooms on a floor.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Zone {
+  /// Inside the room with the given index.
+  Inside(usize),
+  /// On the wall of the room with the given index.
+  Wall(usize),
+  /// On the corner of some room.
+  Corner,
+}
+
+/// Which points lie in or on the walls of the rooms on a floor, as built by
+/// [`Floor::zones()`].
+///
+/// Carving corridors doesn't move any rooms, so one `Zones` can be reused for
+/// every corridor carved between the same set of rooms.
+pub struct Zones(HashMap<Point, Zone>);
+
+impl Floor {
+  /// Carves a corridor between `from` and `to`.
+  ///
+  /// The corridor is routed with A*, using costs that steer it around any
+  /// rooms other than the ones `from` and `to` are in, favor running along
+  /// existing corridors, and favor entering rooms through existing doorways.
+  /// Corridors never pass through the corners of rooms, so they always meet
+  /// a room's wall head-on, where [`Floor::place_doors()`] can put a door.
+  /// They also never dig through walls inside of rooms, which only prefabs
+  /// have, so that the layout of a prefab is left intact.
+  ///
+  /// Corridors only move orthogonally, and are lined with walls wherever
+  /// there was nothing before.
+  ///
+  /// `zones` must have been built by [`Floor::zones()`] since the last time a
+  /// room was added.
+  ///
+  /// Returns whether a route was found; if there isn't one, nothing is carved.
+  pub fn add_corridor(
+    &mut self,
+    zones: &Zones,
+    from: Point,
+    to: Point,
+  ) -> bool {
+    let zones = &zones.0;
+    let ends = [from, to]
+      .iter()
+      .filter_map(|p| match zones.get(p) {
+        Some(&Zone::Inside(i)) => Some(i),
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+
+    let bounds = {
+      let start = Point::new(from.x().min(to.x()), from.y().min(to.y()));
+      let end = Point::new(from.x().max(to.x()), from.y().max(to.y()));
+      let margin = Point::new(MARGIN, MARGIN);
+      Rect::new(start - margin, end + margin + Point::new(1, 1))
+    };
+
+    let path = graph::a_star(
+      from,
+      to,
+      |a, b| {
+        let is_inner_wall = matches!(zones.get(&b), Some(Zone::Inside(_)))
+          && self.tile(b) == Tile::Wall;
+        (a.x() == b.x() || a.y() == b.y())
+          && bounds.contains(b)
+          && zones.get(&b) != Some(&Zone::Corner)
+          && !is_inner_wall
+      },
+      |_, b| {
+        let tile = match self.tile(b) {
+          Tile::Void => VOID_COST,
+          Tile::Wall => WALL_COST,
+          _ => OPEN_COST,
+        };
+        let penalty = match zones.get(&b) {
+          Some(Zone::Inside(i)) | Some(Zone::Wall(i)) if !ends.contains(i) => {
+            ROOM_PENALTY
+          }
+          _ => 0.0,
+        };
+        tile + penalty
+      },
+      |p| (p - to).manhattan() as f64 * OPEN_COST,
+    );
+
+    let path = match path {
+      Some(path) => path,
+      None => return false,
+    };
+    for p in path {
+      let slot = self.chunk_mut(p).tile_mut(p);
+      if *slot < Tile::Ground {
+        *slot = Tile::Ground;
+      }
+
+      for dx in -1..=1 {
+        for dy in -1..=1 {
+          let n = p + Point::new(dx, dy);
+          let slot = self.chunk_mut(n).tile_mut(n);
+          if *slot == Tile::Void {
+            *slot = Tile::Wall;
+          }
+        }
+      }
+    }
+    true
+  }
+
+  /// Works out which points lie in or on the walls of rooms, for
+  /// [`Floor::add_corridor()`].
+  pub fn zones(&self) -> Zones {
+    let mut zones = HashMap::new();
+    for (i, room) in self.rooms.iter().enumerate() {
+      let (start, end) = room.rect.corners();
+      let last = end - Point::new(1, 1);
+      for p in room.rect.points() {
+        let on_x = p.x() == start.x() || p.x() == last.x();
+        let on_y = p.y() == start.y() || p.y() == last.y();
+        let zone = match (on_x, on_y) {
+          (true, true) => Zone::Corner,
+          (false, false) => Zone::Inside(i),
+          _ => Zone::Wall(i),
+        };
+        zones.insert(p, zone);
+      }
+    }
+    Zones(zones)
+  }
+}
@@ -0,0 +1,562 @@
+//! Multi-level dungeons.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::f64::consts::FRAC_PI_3;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::iter;
+use std::mem;
+
+use legion::query::any;
+use legion::query::component;
+use legion::Entity;
+use legion::IntoQuery as _;
+use legion::Resources;
+use legion::World;
+use rand::seq::SliceRandom as _;
+use rand::Rng;
+
+use crate::actor::ai::Chase;
+use crate::actor::ai::Flee;
+use crate::actor::ai::Fov;
+use crate::actor::ai::Pathfind;
+use crate::actor::ai::Tactic;
+use crate::actor::ai::VisionCone;
+use crate::actor::ai::Wander;
+use crate::actor::base::Digger;
+use crate::actor::base::Health;
+use crate::actor::base::MoveDelay;
+use crate::actor::base::Oriented;
+use crate::actor::base::Position;
+use crate::actor::base::Sprite;
+use crate::actor::base::Swimmer;
+use crate::actor::base::Tangible;
+use crate::actor::base::Trapwise;
+use crate::actor::hazard::LastPosition;
+use crate::actor::light::Light;
+use crate::actor::light::LightMap;
+use crate::actor::player::Player;
+use crate::geo::fov;
+use crate::geo::Dir;
+use crate::geo::Point;
+use crate::geo::Rect;
+use crate::gfx::texel::colors;
+use crate::gfx::texel::Texel;
+use crate::map::prefab::Spawn;
+use crate::map::Floor;
+use crate::map::Memory;
+use crate::map::Prefabs;
+use crate::map::RoomKind;
+use crate::map::Tile;
+use crate::map::Tiles;
+use crate::rng;
+use crate::save;
+use crate::save::Decode;
+use crate::save::Encode;
+use crate::save::Registry;
+
+const MAGIC: &[u8; 4] = b"CRWL";
+/// The version of the world save format.
+///
+/// Components are saved without any versioning of their own, so saves from
+/// older versions can't be loaded at all; see [`Dungeon::load()`].
+///
+/// - Version 2 saves each [`Fov`]'s algorithm.
+/// - Version 3 only saves memories for a [`Fov`] that has them.
+/// - Version 4 saves memories and point sets chunk by chunk.
+/// - Version 5 saves whether each [`Pathfind`] is fleeing.
+const VERSION: u32 = 5;
+
+/// A direction to take a staircase in.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Stairs {
+  /// Towards the surface.
+  Up,
+  /// Deeper into the dungeon.
+  Down,
+}
+
+/// Resource: A dungeon, consisting of a stack of levels.
+///
+/// Only one level is active at a time: its [`Floor`] is a resource of its own,
+/// and its actors live in the main `World`. Every other level that has been
+/// visited is stashed away in the `Dungeon`, along with a separate `World` for
+/// its actors, so that it can be picked up exactly where it was left.
+///
+/// Levels are generated lazily, the first time they are visited.
+pub struct Dungeon {
+  depth: usize,
+  levels: HashMap<usize, Level>,
+  pending: Option<Stairs>,
+}
+
+/// A level that is not currently active.
+struct Level {
+  floor: Floor,
+  world: World,
+  /// The player's memory of `floor` when they left.
+  memory: Memory,
+}
+
+/// Component: Temporarily marks actors that are moving to another level.
+struct Travelling;
+
+impl Dungeon {
+  /// Creates a new `Dungeon`, whose active level is the topmost one.
+  pub fn new() -> Self {
+    Self {
+      depth: 0,
+      levels: HashMap::new(),
+      pending: None,
+    }
+  }
+
+  /// Returns the depth of the active level; zero is the topmost level.
+  pub fn depth(&self) -> usize {
+    self.depth
+  }
+
+  /// Generates the level at `depth`, returning its floor and a world containing
+  /// its monsters.
+  ///
+  /// Every level has a down staircase; every level but the topmost also has an
+  /// up staircase at the center of its first room. The down staircase is as
+  /// far away as possible, at the center of another room, or, if there is only
+  /// the one room, in some other corner of it. Rooms are given themes at
+  /// random, which decide how many monsters they hold.
+  pub fn generate(
+    depth: usize,
+    rng: &mut impl Rng,
+    tiles: &Tiles,
+    prefabs: &Prefabs,
+  ) -> (Floor, World) {
+    // Generators can, very rarely, leave no room for the stairs, in which case
+    // we simply try again.
+    let (mut floor, arrival, exit) = loop {
+      let floor = Self::generate_floor(depth, rng, tiles, prefabs);
+      if let Some((arrival, exit)) = find_stairs(&floor, tiles) {
+        break (floor, arrival, exit);
+      }
+    };
+    if depth > 0 {
+      *floor.chunk_mut(arrival).tile_mut(arrival) = Tile::StairsUp;
+    }
+    *floor.chunk_mut(exit).tile_mut(exit) = Tile::StairsDown;
+    let rooms = floor.rooms().to_vec();
+
+    // Populate every room but the one the player arrives in, according to its
+    // kind, steering clear of anything the generator already placed.
+    let mut world = World::default();
+    let mut occupied = HashSet::new();
+    for (p, spawn) in floor.take_spawns() {
+      match spawn {
+        Spawn::Monster => spawn_monster(&mut world, rng, p),
+      }
+      occupied.insert(p);
+    }
+    for room in &rooms[1..] {
+      let count = room.kind.monsters();
+      let center = room.center();
+      if count == 0 || occupied.contains(&center) {
+        continue;
+      }
+      spawn_monster(&mut world, rng, center);
+      occupied.insert(center);
+
+      let spots = room
+        .rect
+        .points()
+        .filter(|p| !occupied.contains(p))
+        .filter(|&p| {
+          let def = tiles.get(floor.tile(p));
+          def.is_walkable() && def.damage == 0
+        })
+        .collect::<Vec<_>>();
+      for &p in spots.choose_multiple(rng, count - 1) {
+        spawn_monster(&mut world, rng, p);
+        occupied.insert(p);
+      }
+    }
+
+    // Hang lamps in the first room and some of the others; caverns are always
+    // left dark.
+    for (i, room) in rooms.iter().enumerate() {
+      if room.kind == RoomKind::Cavern || (i > 0 && !rng.gen_bool(LIT_CHANCE)) {
+        continue;
+      }
+      // Stretch the light's range so that it reaches into the corners.
+      let (w, h) = (room.rect.width(), room.rect.height());
+      world.push((
+        Position(room.center()),
+        Light {
+          range: Point::new(w * 3 / 4 + 1, h * 3 / 4 + 1),
+          color: colors::LIGHTYELLOW,
+        },
+      ));
+    }
+
+    (floor, world)
+  }
+
+  /// Generates the map of the level at `depth`, which alternates between the
+  /// different generators.
+  fn generate_floor(
+    depth: usize,
+    rng: &mut impl Rng,
+    tiles: &Tiles,
+    prefabs: &Prefabs,
+  ) -> Floor {
+    let mut floor = Floor::new();
+    let bounds = Rect::with_dims(200, 200).centered_on(Point::zero());
+    match depth % 3 {
+      0 => {
+        floor.rooms_and_corridors(
+          rng,
+          prefabs,
+          50,
+          bounds,
+          Point::new(10, 10),
+          Point::new(30, 30),
+          0.3,
+        );
+        floor.connect_rooms(tiles);
+      }
+      1 => {
+        floor.bsp(rng, prefabs, bounds, Point::new(25, 25), Point::new(8, 8))
+      }
+      _ => floor.caves(rng, bounds, 0.45, 5),
+    }
+    floor.link_rooms(tiles);
+    floor.theme_rooms(rng);
+    floor.add_terrain(rng);
+    floor.add_secrets(rng, tiles);
+    floor
+  }
+
+  /// Schedules a trip up or down a staircase, which will happen at the next
+  /// call to [`Dungeon::travel()`].
+  pub fn take_stairs(&mut self, stairs: Stairs) {
+    self.pending = Some(stairs);
+  }
+
+  /// Performs any trip scheduled with [`Dungeon::take_stairs()`].
+  ///
+  /// The player, along with any monsters right next to them, is moved to the
+  /// matching staircase on the new level; everyone else stays behind. If
+  /// someone on the new level is standing on the staircase, the player arrives
+  /// next to it instead, and if every spot around it is taken, the trip is
+  /// called off.
+  ///
+  /// This needs to move entities between worlds, so it cannot be done from
+  /// inside a system; it should be called once per frame, after the schedule
+  /// has executed.
+  pub fn travel(world: &mut World, resources: &mut Resources) {
+    let mut dungeon = resources.get_mut::<Dungeon>().unwrap();
+    let stairs = match dungeon.pending.take() {
+      Some(stairs) => stairs,
+      None => return,
+    };
+    let (target, arrival_tile) = match stairs {
+      Stairs::Up if dungeon.depth == 0 => return,
+      Stairs::Up => (dungeon.depth - 1, Tile::StairsDown),
+      Stairs::Down => (dungeon.depth + 1, Tile::StairsUp),
+    };
+
+    let mut level = dungeon.levels.remove(&target).unwrap_or_else(|| {
+      let tiles = resources.get::<Tiles>().unwrap();
+      let prefabs = resources.get::<Prefabs>().unwrap();
+      let mut rng = resources.get_mut::<rng::Rng>().unwrap();
+      let (floor, world) =
+        Self::generate(target, rng.stream("map"), &tiles, &prefabs);
+      Level {
+        floor,
+        world,
+        memory: Memory::new(),
+      }
+    });
+
+    // Figure out who's coming along, and where they'll end up. Players get the
+    // staircase itself, unless someone is already standing on it, and then the
+    // spots around it; followers that don't fit get left behind.
+    let arrival = level.floor.find_tile(arrival_tile).unwrap();
+    let tiles = resources.get::<Tiles>().unwrap();
+    let occupied = <&Position>::query()
+      .filter(component::<Tangible>())
+      .iter(&level.world)
+      .map(|p| p.0)
+      .collect::<HashSet<_>>();
+    let mut spots = iter::once(arrival)
+      .chain(Dir::all().iter().map(|d| arrival + d.to_point::<i64>()))
+      .filter(|&p| tiles.get(level.floor.tile(p)).is_walkable())
+      .filter(|p| !occupied.contains(p))
+      .collect::<Vec<_>>()
+      .into_iter();
+
+    let players = <(Entity, &Position)>::query()
+      .filter(component::<Player>())
+      .iter(world)
+      .map(|(&e, p)| (e, p.0))
+      .collect::<Vec<_>>();
+    let mut travellers = Vec::new();
+    for &(e, _) in &players {
+      match spots.next() {
+        Some(spot) => travellers.push((e, spot)),
+        None => {
+          // The staircase is completely blocked off, so nobody goes anywhere.
+          dungeon.levels.insert(target, level);
+          return;
+        }
+      }
+    }
+    for (&e, p) in <(Entity, &Position)>::query()
+      .filter(component::<Pathfind>())
+      .iter(world)
+    {
+      let is_adjacent = players.iter().any(|&(_, q)| {
+        let d = p.0 - q;
+        d.x().abs() <= 1 && d.y().abs() <= 1
+      });
+      if !is_adjacent {
+        continue;
+      }
+      if let Some(spot) = spots.next() {
+        travellers.push((e, spot));
+      }
+    }
+
+    // Stash away everything that isn't travelling, including what the player
+    // remembers of this level.
+    for &(e, _) in &travellers {
+      world.entry(e).unwrap().add_component(Travelling);
+    }
+    let mut stash = World::default();
+    stash.move_from(world, &!component::<Travelling>());
+
+    let mut memory = Memory::new();
+    for fov in <&mut Fov>::query()
+      .filter(component::<Player>())
+      .iter_mut(world)
+    {
+      if let Some(m) = &mut fov.memory {
+        memory.extend(m.iter());
+        *m = level.memory.clone();
+      }
+      fov.visible.clear();
+    }
+
+    let floor =
+      mem::replace(&mut *resources.get_mut::<Floor>().unwrap(), level.floor);
+    if let Some(mut lights) = resources.get_mut::<LightMap>() {
+      lights.reset();
+    }
+    let depth = dungeon.depth;
+    dungeon.levels.insert(
+      depth,
+      Level {
+        floor,
+        world: stash,
+        memory,
+      },
+    );
+    dungeon.depth = target;
+
+    // Finally, bring the new level's actors in, and put the travellers in their
+    // new spots.
+    world.move_from(&mut level.world, &any());
+    for (e, spot) in travellers {
+      let mut entry = world.entry(e).unwrap();
+      entry.get_component_mut::<Position>().unwrap().0 = spot;
+      if let Ok(pf) = entry.get_component_mut::<Pathfind>() {
+        pf.reset();
+      }
+      if let Ok(fov) = entry.get_component_mut::<Fov>() {
+        fov.reset();
+      }
+      entry.remove_component::<Travelling>();
+      entry.remove_component::<LastPosition>();
+    }
+  }
+
+  /// Saves the entire game: every level of the dungeon, the actors on each,
+  /// and whatever resources are in `registry`.
+  pub fn save(
+    world: &World,
+    resources: &Resources,
+    registry: &Registry,
+    w: &mut dyn Write,
+  ) -> io::Result<()> {
+    save::write_header(w, MAGIC, VERSION)?;
+
+    let dungeon = resources.get::<Dungeon>().unwrap();
+    dungeon.depth.encode(w)?;
+    resources.get::<Floor>().unwrap().encode(w)?;
+
+    let mut depths = dungeon.levels.keys().copied().collect::<Vec<_>>();
+    depths.sort_unstable();
+    depths.len().encode(w)?;
+    let mut worlds = vec![world];
+    for depth in depths {
+      let level = &dungeon.levels[&depth];
+      depth.encode(w)?;
+      level.floor.encode(w)?;
+      level.memory.encode(w)?;
+      worlds.push(&level.world);
+    }
+
+    registry.save(&worlds, resources, w)
+  }
+
+  /// Loads a game saved with [`Dungeon::save()`], replacing everything in
+  /// `world` and the `Dungeon` and [`Floor`] resources.
+  ///
+  /// Only saves from the current version of the game can be loaded. If
+  /// loading fails, `world`, the `Dungeon` and the `Floor` are left as they
+  /// were, though some of the resources in `registry` may have been loaded.
+  pub fn load(
+    world: &mut World,
+    resources: &mut Resources,
+    registry: &Registry,
+    r: &mut dyn Read,
+  ) -> io::Result<()> {
+    let version = save::expect_header(r, MAGIC, VERSION)?;
+    if version != VERSION {
+      return Err(save::invalid(format!(
+        "saves from version {} are no longer supported",
+        version
+      )));
+    }
+
+    let depth = usize::decode(r)?;
+    let floor = Floor::decode(r)?;
+    let mut levels = Vec::new();
+    for _ in 0..usize::decode(r)? {
+      let depth = usize::decode(r)?;
+      let floor = Floor::decode(r)?;
+      let memory = Memory::decode(r)?;
+      levels.push((depth, floor, memory));
+    }
+
+    let mut worlds = registry.load(r, resources)?;
+    if worlds.len() != levels.len() + 1 {
+      return Err(save::invalid(format!(
+        "expected {} worlds, got {}",
+        levels.len() + 1,
+        worlds.len()
+      )));
+    }
+
+    // Systems hold on to state about the world they run on, so rather than
+    // replacing it, we empty it out and move the saved entities in.
+    world.clear();
+    world.move_from(&mut worlds[0], &any());
+    let levels = levels
+      .into_iter()
+      .zip(worlds.drain(1..))
+      .map(|((depth, floor, memory), world)| {
+        (
+          depth,
+          Level {
+            floor,
+            world,
+            memory,
+          },
+        )
+      })
+      .collect();
+
+    resources.insert(floor);
+    if let Some(mut lights) = resources.get_mut::<LightMap>() {
+      lights.reset();
+    }
+    resources.insert(Dungeon {
+      depth,
+      levels,
+      pending: None,
+    });
+    Ok(())
+  }
+}
+
+/// Works out where the up and down staircases of `floor` go, returning `None`
+/// if there is nowhere to put them.
+///
+/// See [`Dungeon::generate()`].
+fn find_stairs(floor: &Floor, tiles: &Tiles) -> Option<(Point, Point)> {
+  let rooms = floor.rooms();
+  let arrival = rooms.first()?.center();
+  let exits = match rooms.len() {
+    1 => rooms[0]
+      .rect
+      .points()
+      .filter(|&p| p != arrival && tiles.get(floor.tile(p)).is_walkable())
+      .collect(),
+    _ => rooms[1..].iter().map(|r| r.center()).collect::<Vec<_>>(),
+  };
+  let exit = exits
+    .into_iter()
+    .max_by_key(|&p| (p - arrival).manhattan())?;
+  Some((arrival, exit))
+}
+
+/// The chance that a room other than the first is lit.
+const LIT_CHANCE: f64 = 0.6;
+
+/// The chance that a monster runs away from the player instead of chasing
+/// them.
+const COWARD_CHANCE: f64 = 0.15;
+
+/// The chance that a monster can swim.
+const SWIMMER_CHANCE: f64 = 0.25;
+
+/// The chance that a monster can tunnel through walls.
+const DIGGER_CHANCE: f64 = 0.1;
+
+/// The chance that a monster glows in the dark.
+const GLOW_CHANCE: f64 = 0.2;
+
+/// How far to either side of where they are facing monsters can see, in
+/// radians.
+const VISION_HALF_WIDTH: f64 = FRAC_PI_3;
+
+/// How close, vertically, something has to be for a monster to notice it
+/// whichever way it is facing; horizontally, it is twice that, like the rest
+/// of its field of view.
+const PERIPHERAL_RADIUS: i64 = 2;
+
+/// Spawns a monster at `pos`.
+fn spawn_monster(world: &mut World, rng: &mut impl Rng, pos: Point) {
+  let facing = *Dir::all().choose(rng).unwrap();
+  let (sprite, tactic): (_, Box<dyn Tactic>) = if rng.gen_bool(COWARD_CHANCE) {
+    ('k', Box::new(Flee))
+  } else {
+    ('K', Box::new(Chase::new()))
+  };
+  let monster = world.push((
+    Position(pos),
+    Oriented(facing),
+    Tangible,
+    Fov::new(Point::new(20, 10), &fov::Milazzo).with_cone(VisionCone {
+      half_width: VISION_HALF_WIDTH,
+      peripheral: Point::new(PERIPHERAL_RADIUS * 2, PERIPHERAL_RADIUS),
+    }),
+    Sprite(Texel::new(sprite)),
+    Pathfind::new(vec![tactic, Box::new(Wander)]),
+    Health::new(20),
+    MoveDelay(0),
+    Trapwise,
+  ));
+  if rng.gen_bool(SWIMMER_CHANCE) {
+    world.entry(monster).unwrap().add_component(Swimmer);
+  }
+  if rng.gen_bool(DIGGER_CHANCE) {
+    world.entry(monster).unwrap().add_component(Digger);
+  }
+  if rng.gen_bool(GLOW_CHANCE) {
+    world.entry(monster).unwrap().add_component(Light {
+      range: Point::new(4, 2),
+      color: colors::PALEGREEN,
+    });
+  }
+}
@@ -0,0 +1,76 @@
+//! Remembering what parts of a floor looked like.
+
+use std::collections::HashMap;
+
+use crate::geo::Point;
+use crate::geo::PointSet;
+use crate::geo::Rect;
+use crate::map::normalize;
+use crate::map::Chunk;
+use crate::map::Tile;
+
+/// What an actor remembers of a [`Floor`]: what every tile it has seen looked
+/// like when it last saw it.
+///
+/// Remembered tiles are stored in chunks, just like a `Floor`'s, alongside a
+/// [`PointSet`] of which tiles have been seen at all, so that even memories of
+/// a large floor stay compact.
+///
+/// [`Floor`]: crate::map::Floor
+#[derive(Clone, Default)]
+pub struct Memory {
+  // Invariant: every chunk containing a point of `seen` is present.
+  pub(super) seen: PointSet,
+  pub(super) chunks: HashMap<Point, Chunk>,
+}
+
+impl Memory {
+  /// Creates a new `Memory` that hasn't seen anything yet.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Returns what the tile at `p` looked like when it was last seen, if it has
+  /// been seen at all.
+  pub fn get(&self, p: Point) -> Option<Tile> {
+    if !self.seen.contains(p) {
+      return None;
+    }
+    Some(*self.chunks[&normalize(p)].tile(p))
+  }
+
+  /// Records that the tile at `p` looked like `tile`.
+  pub fn insert(&mut self, p: Point, tile: Tile) {
+    self.seen.insert(p);
+    let pos = normalize(p);
+    let chunk = self.chunks.entry(pos).or_insert_with(|| Chunk::new(pos));
+    *chunk.tile_mut(p) = tile;
+  }
+
+  /// Returns an iterator over every remembered tile.
+  pub fn iter(&self) -> impl Iterator<Item = (Point, Tile)> + '_ {
+    self
+      .seen
+      .iter()
+      .map(move |p| (p, *self.chunks[&normalize(p)].tile(p)))
+  }
+
+  /// Returns an iterator over every remembered tile within `rect`.
+  pub fn iter_in(
+    &self,
+    rect: Rect,
+  ) -> impl Iterator<Item = (Point, Tile)> + '_ {
+    self
+      .seen
+      .iter_in(rect)
+      .map(move |p| (p, *self.chunks[&normalize(p)].tile(p)))
+  }
+}
+
+impl Extend<(Point, Tile)> for Memory {
+  fn extend<I: IntoIterator<Item = (Point, Tile)>>(&mut self, iter: I) {
+    for (p, tile) in iter {
+      self.insert(p, tile);
+    }
+  }
+}
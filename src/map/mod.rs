@@ -3,34 +3,59 @@
 #![allow(missing_docs)]
 
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::convert::TryInto as _;
 
-use rand::distributions::Bernoulli;
 use rand::distributions::Distribution as _;
-use rand::distributions::Open01;
 use rand::distributions::Uniform;
+use rand::Rng;
 
+use crate::geo::Dir;
 use crate::geo::Point;
 use crate::geo::Rect;
 use crate::geo::RectVec;
 use crate::gfx::texel::Texel;
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
-pub enum Tile {
-  Void,
-  Wall,
-  Ground,
-}
-
-const WIDTH: usize = 32;
+mod bsp;
+mod cave;
+mod corridor;
+pub mod dungeon;
+mod memory;
+pub mod prefab;
+pub mod room;
+mod save;
+mod secrets;
+pub mod stream;
+mod terrain;
+pub mod tile;
+
+pub use cave::EndlessCaves;
+pub use corridor::Zones;
+pub use dungeon::Dungeon;
+pub use memory::Memory;
+pub use prefab::Prefabs;
+pub use room::Room;
+pub use room::RoomKind;
+pub use tile::Tile;
+pub use tile::Tiles;
+
+/// The width and height of each [`Chunk`].
+pub(crate) const WIDTH: usize = 32;
+
+/// The chance that a generator places a prefab instead of an ordinary room.
+const PREFAB_CHANCE: f64 = 0.15;
 
 fn normalize(pos: Point) -> Point {
   Point::new(pos.x() & !(WIDTH as i64 - 1), pos.y() & !(WIDTH as i64 - 1))
 }
 
+#[derive(Clone)]
 pub struct Chunk {
   pos: Point,
   tiles: Box<[Tile; WIDTH * WIDTH]>,
+  // The floor revision at which this chunk last changed.
+  revision: u64,
 }
 
 impl Chunk {
@@ -41,17 +66,22 @@ impl Chunk {
         .into_boxed_slice()
         .try_into()
         .unwrap(),
+      revision: 0,
     }
   }
 
-  pub fn image(&self) -> RectVec<Texel> {
+  /// Returns the [`Floor::revision()`] at which this chunk last changed.
+  ///
+  /// Anything computed from this chunk's tiles is out of date once this moves
+  /// past the revision it was computed at.
+  pub fn revision(&self) -> u64 {
+    self.revision
+  }
+
+  pub fn image(&self, tiles: &Tiles) -> RectVec<Texel> {
     let mut rect = RectVec::new(self.rect(), Texel::new('\0'));
     for (tx, tile) in rect.data_mut().iter_mut().zip(self.tiles.iter()) {
-      match tile {
-        Tile::Void => {}
-        Tile::Wall => *tx = Texel::new('+'),
-        Tile::Ground => *tx = Texel::new('.'),
-      };
+      *tx = tiles.get(*tile).texel;
     }
     rect
   }
@@ -80,7 +110,15 @@ pub struct Floor {
   // infinity.
   chunks: HashMap<Point, Chunk>,
 
-  rooms: Vec<Rect>,
+  rooms: Vec<Room>,
+
+  // Things that generators have placed, which have yet to be spawned. These
+  // are not saved, since they are taken right after generation.
+  spawns: Vec<(Point, prefab::Spawn)>,
+
+  // Bumped every time a chunk changes. This is not saved; caches that depend
+  // on it don't outlive the game they were built in.
+  revision: u64,
 }
 
 impl Floor {
@@ -88,19 +126,73 @@ impl Floor {
     Floor {
       chunks: HashMap::new(),
       rooms: Vec::new(),
+      spawns: Vec::new(),
+      revision: 0,
     }
   }
 
+  /// Returns this floor's current revision, which increases every time any of
+  /// its tiles change.
+  ///
+  /// Caches of anything computed from the floor's tiles, like paths and fields
+  /// of view, should record the revision they were computed at, and check
+  /// [`Floor::changed_since()`] before being reused.
+  pub fn revision(&self) -> u64 {
+    self.revision
+  }
+
+  /// Returns whether the chunk containing `pos` has changed since `revision`.
+  ///
+  /// Chunks are only tracked as a whole, so this may report a change even if
+  /// the tile at `pos` itself is unchanged.
+  pub fn changed_since(&self, revision: u64, pos: Point) -> bool {
+    self.chunk(pos).map_or(0, Chunk::revision) > revision
+  }
+
+  /// Returns whether any chunk overlapping `rect` has changed since
+  /// `revision`; see [`Floor::changed_since()`].
+  pub fn changed_within(&self, revision: u64, rect: Rect) -> bool {
+    self.chunks_in(rect).any(|(_, c)| c.revision > revision)
+  }
+
+  /// Bumps this floor's revision, returning the new one.
+  fn bump_revision(&mut self) -> u64 {
+    self.revision += 1;
+    self.revision
+  }
+
   pub fn chunk(&self, pos: Point) -> Option<&Chunk> {
     self.chunks.get(&normalize(pos))
   }
 
+  /// Returns the `Tile` at the given position.
+  ///
+  /// Positions outside of any chunk are `Tile::Void`.
+  pub fn tile(&self, pos: Point) -> Tile {
+    self.chunk(pos).map(|c| *c.tile(pos)).unwrap_or(Tile::Void)
+  }
+
+  /// Returns some position at which `tile` occurs, if there is one.
+  pub fn find_tile(&self, tile: Tile) -> Option<Point> {
+    self.chunks.values().find_map(|chunk| {
+      let idx = chunk.tiles.iter().position(|&t| t == tile)?;
+      let offset = Point::new((idx % WIDTH) as i64, (idx / WIDTH) as i64);
+      Some(chunk.pos + offset)
+    })
+  }
+
   /// Returns the `Chunk` containing the given position.
+  ///
+  /// The chunk is assumed to be about to change, so this moves its revision
+  /// forward; see [`Floor::revision()`].
   pub fn chunk_mut(&mut self, pos: Point) -> &mut Chunk {
-    self
+    let revision = self.bump_revision();
+    let chunk = self
       .chunks
       .entry(normalize(pos))
-      .or_insert_with(move || Chunk::new(normalize(pos)))
+      .or_insert_with(move || Chunk::new(normalize(pos)));
+    chunk.revision = revision;
+    chunk
   }
 
   pub fn chunks_in(
@@ -112,7 +204,7 @@ impl Floor {
       .filter_map(move |r| self.chunk(r.corners().0).map(move |c| (r, c)))
   }
 
-  pub fn rooms(&self) -> &[Rect] {
+  pub fn rooms(&self) -> &[Room] {
     &self.rooms
   }
 
@@ -131,88 +223,272 @@ impl Floor {
     }
   }
 
-  pub fn add_horizontal(&mut self, start: Point, len: i64) {
-    for dx in 0..=len.abs() {
-      for dy in -1..=1 {
-        let tile = match dy {
-          0 => Tile::Ground,
-          _ => Tile::Wall,
-        };
-
-        let p = start + Point::new(dx * len.signum(), dy);
-        let slot = self.chunk_mut(p).tile_mut(p);
-        if tile > *slot {
-          *slot = tile;
-        }
-      }
-    }
-  }
-
-  pub fn add_vertical(&mut self, start: Point, len: i64) {
-    for dy in 0..=len.abs() {
-      for dx in -1..=1 {
-        let tile = match dx {
-          0 => Tile::Ground,
-          _ => Tile::Wall,
-        };
-
-        let p = start + Point::new(dx, dy * len.signum());
-        let slot = self.chunk_mut(p).tile_mut(p);
-        if tile > *slot {
-          *slot = tile;
-        }
-      }
-    }
-  }
-
+  /// Scatters up to `count` non-overlapping rooms across `bounds`, and joins
+  /// them up with [`Floor::connect_room_graph()`].
   pub fn rooms_and_corridors(
     &mut self,
+    rng: &mut impl Rng,
+    prefabs: &Prefabs,
     count: usize,
     bounds: Rect,
     min_size: Point,
     max_size: Point,
+    loops: f64,
   ) {
-    let mut rng = rand::thread_rng();
-
     for _ in 0..count {
       let (start, end) = bounds.corners();
-      let x = Uniform::new(start.x(), end.x()).sample(&mut rng);
-      let y = Uniform::new(start.y(), end.y()).sample(&mut rng);
+      let x = Uniform::new(start.x(), end.x()).sample(rng);
+      let y = Uniform::new(start.y(), end.y()).sample(rng);
 
-      let w = Uniform::new(min_size.x(), max_size.y()).sample(&mut rng);
-      let h = Uniform::new(min_size.x(), max_size.y()).sample(&mut rng);
+      let w = Uniform::new(min_size.x(), max_size.y()).sample(rng);
+      let h = Uniform::new(min_size.x(), max_size.y()).sample(rng);
+
+      let prefab = match rng.gen_bool(PREFAB_CHANCE) {
+        true => prefabs.pick(rng, max_size),
+        false => None,
+      };
+      let (dims, kind) = match prefab {
+        Some((prefab, transform)) => (prefab.dims(transform), prefab.kind()),
+        None => (Point::new(w, h), RoomKind::Plain),
+      };
 
-      let room = Rect::with_dims(w as _, h as _).centered_on(Point::new(x, y));
-      if self.rooms.iter().any(|r| r.intersect(room).is_some()) {
+      let room =
+        Rect::with_dims(dims.x(), dims.y()).centered_on(Point::new(x, y));
+      if self.rooms.iter().any(|r| r.rect.intersect(room).is_some()) {
         continue;
       }
 
-      self.add_room(room);
+      match prefab {
+        Some((prefab, transform)) => {
+          self.add_prefab(prefab, transform, room.upper_left());
+        }
+        None => self.add_room(room),
+      }
+
+      self.rooms.push(Room::new(room, kind));
+    }
 
-      if let Some(prev) = self.rooms.last().cloned() {
-        let x: f64 = Open01.sample(&mut rng);
-        if x > 0.7 {
-          continue;
+    self.connect_room_graph(rng, loops);
+  }
+
+  /// Ensures that every room is reachable from the first one.
+  ///
+  /// This flood-fills the walkable tiles reachable from the center of the
+  /// first room. While some room's center was not reached, the closest pair of
+  /// reached and unreached rooms is joined with a corridor, and the fill is
+  /// repeated.
+  ///
+  /// Each pair of rooms is only tried once, so if no corridor can be routed
+  /// between a pair, the next closest one is tried instead. Rooms that can't be
+  /// joined to any reached room at all are left unreachable.
+  pub fn connect_rooms(&mut self, tiles: &Tiles) {
+    let start = match self.rooms.first() {
+      Some(room) => room.center(),
+      None => return,
+    };
+
+    let zones = self.zones();
+    let mut tried = HashSet::new();
+    loop {
+      let reached = self.reachable_from(tiles, start);
+      let (connected, disconnected) = self
+        .rooms
+        .iter()
+        .map(|r| r.center())
+        .partition::<Vec<_>, _>(|p| reached.contains(p));
+
+      let closest = connected
+        .iter()
+        .flat_map(|&a| disconnected.iter().map(move |&b| (a, b)))
+        .filter(|pair| !tried.contains(pair))
+        .min_by_key(|&(a, b)| (a - b).manhattan());
+      match closest {
+        Some((from, to)) => {
+          tried.insert((from, to));
+          self.add_corridor(&zones, from, to);
         }
+        None => break,
+      }
+    }
+
+    self.place_doors();
+  }
 
-        if Bernoulli::new(0.5).unwrap().sample(&mut rng) {
-          self.add_horizontal(
-            prev.center(),
-            room.center().x() - prev.center().x(),
-          );
-          self
-            .add_vertical(room.center(), prev.center().y() - room.center().y());
+  /// Places closed doors wherever a corridor passes through the wall of a room.
+  ///
+  /// Only openings with wall on both sides get a door; this way, corridors that
+  /// run along a room's wall and open it up completely are left alone.
+  ///
+  /// Afterwards, every door in a room's wall, including any that were there
+  /// already, is recorded in [`Room::doors`].
+  pub fn place_doors(&mut self) {
+    for i in 0..self.rooms.len() {
+      let room = self.rooms[i].rect;
+      let (start, end) = room.corners();
+      let last = end - Point::new(1, 1);
+      for p in room.boundary() {
+        let along = if p.x() == start.x() || p.x() == last.x() {
+          Point::new(0, 1)
         } else {
-          self
-            .add_vertical(prev.center(), room.center().y() - prev.center().y());
-          self.add_horizontal(
-            room.center(),
-            prev.center().x() - room.center().x(),
-          );
+          Point::new(1, 0)
+        };
+
+        let is_corner = (p.x() == start.x() || p.x() == last.x())
+          && (p.y() == start.y() || p.y() == last.y());
+        if !is_corner
+          && self.tile(p) == Tile::Ground
+          && self.tile(p + along) == Tile::Wall
+          && self.tile(p - along) == Tile::Wall
+        {
+          *self.chunk_mut(p).tile_mut(p) = Tile::DoorClosed;
+        }
+      }
+
+      let doors = room
+        .boundary()
+        .filter(|&p| matches!(self.tile(p), Tile::DoorClosed | Tile::DoorOpen))
+        .collect();
+      self.rooms[i].doors = doors;
+    }
+  }
+
+  /// Opens the tile at `pos`, if it can be opened.
+  ///
+  /// Returns whether anything was opened.
+  pub fn open(&mut self, tiles: &Tiles, pos: Point) -> bool {
+    match tiles.get(self.tile(pos)).opens_into {
+      Some(tile) => {
+        *self.chunk_mut(pos).tile_mut(pos) = tile;
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Digs out the tile at `pos`, if it can be dug.
+  ///
+  /// Any empty space around the new opening is walled in, the same way
+  /// corridors are. Returns whether anything was dug.
+  pub fn dig(&mut self, tiles: &Tiles, pos: Point) -> bool {
+    let tile = match tiles.get(self.tile(pos)).digs_into {
+      Some(tile) => tile,
+      None => return false,
+    };
+    *self.chunk_mut(pos).tile_mut(pos) = tile;
+
+    for &d in &Dir::all() {
+      let n = pos + d.to_point::<i64>();
+      if self.tile(n) == Tile::Void {
+        *self.chunk_mut(n).tile_mut(n) = Tile::Wall;
+      }
+    }
+    true
+  }
+
+  /// Reveals the tile at `pos`, if it is hidden.
+  ///
+  /// Returns whether anything was revealed.
+  pub fn reveal(&mut self, tiles: &Tiles, pos: Point) -> bool {
+    match tiles.get(self.tile(pos)).reveals_into {
+      Some(tile) => {
+        *self.chunk_mut(pos).tile_mut(pos) = tile;
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Returns every point reachable from `start` by walking, opening doors
+  /// along the way as necessary.
+  pub fn reachable_from(&self, tiles: &Tiles, start: Point) -> HashSet<Point> {
+    let is_walkable = |p| tiles.get(self.tile(p)).is_passable();
+
+    let mut reached = HashSet::new();
+    let mut queue = VecDeque::new();
+    if is_walkable(start) {
+      reached.insert(start);
+      queue.push_back(start);
+    }
+    while let Some(p) = queue.pop_front() {
+      for &d in &Dir::all() {
+        let n = p + d.to_point::<i64>();
+        if is_walkable(n) && reached.insert(n) {
+          queue.push_back(n);
         }
       }
+    }
+    reached
+  }
+}
 
-      self.rooms.push(room);
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::rng;
+
+  /// How many seeds to try each generator with.
+  const SEEDS: u64 = 50;
+
+  /// Generates a floor with `generate` for each of a range of seeds, connects
+  /// it, and checks that every room can be reached from the first one.
+  fn assert_connected(generate: impl Fn(&mut Floor, &mut rng::Stream)) {
+    let tiles = Tiles::builtin();
+    for seed in 0..SEEDS {
+      let mut rng = rng::Rng::new(seed);
+      let mut floor = Floor::new();
+      generate(&mut floor, rng.stream("map"));
+      floor.connect_rooms(&tiles);
+
+      assert!(!floor.rooms().is_empty(), "seed {} made no rooms", seed);
+      let reached = floor.reachable_from(&tiles, floor.rooms()[0].center());
+      for room in floor.rooms() {
+        assert!(
+          reached.contains(&room.center()),
+          "seed {}: room at {:?} is unreachable",
+          seed,
+          room.rect
+        );
+      }
     }
   }
+
+  fn bounds() -> Rect {
+    Rect::with_dims(200, 200).centered_on(Point::zero())
+  }
+
+  #[test]
+  fn rooms_and_corridors_are_connected() {
+    let prefabs = Prefabs::builtin();
+    assert_connected(|floor, rng| {
+      floor.rooms_and_corridors(
+        rng,
+        &prefabs,
+        50,
+        bounds(),
+        Point::new(10, 10),
+        Point::new(30, 30),
+        0.3,
+      )
+    });
+  }
+
+  #[test]
+  fn bsp_is_connected() {
+    let prefabs = Prefabs::builtin();
+    assert_connected(|floor, rng| {
+      floor.bsp(
+        rng,
+        &prefabs,
+        bounds(),
+        Point::new(25, 25),
+        Point::new(8, 8),
+      )
+    });
+  }
+
+  #[test]
+  fn caves_are_connected() {
+    assert_connected(|floor, rng| floor.caves(rng, bounds(), 0.45, 5));
+  }
 }
@@ -0,0 +1,532 @@
+//! Prefab vaults.
+//!
+//! A [`Prefab`] is a hand-authored piece of map, which generators can stamp
+//! into a [`Floor`] in place of an ordinary room. Prefabs are loaded from a
+//! data file into a [`Prefabs`] registry.
+//!
+//! See `data/prefabs.txt` for the format.
+
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::path::Path;
+
+use rand::seq::SliceRandom as _;
+use rand::Rng;
+
+use crate::geo::Point;
+use crate::geo::Rect;
+use crate::map::tile::unquote;
+use crate::map::tile::ParseError;
+use crate::map::Floor;
+use crate::map::RoomKind;
+use crate::map::Tile;
+
+/// Something to spawn on a [`Floor`] once it has been generated.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum Spawn {
+  /// A monster.
+  Monster,
+}
+
+impl Spawn {
+  /// Looks up a spawn by the name it is referred to by in data files.
+  fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "monster" => Some(Spawn::Monster),
+      _ => None,
+    }
+  }
+}
+
+/// A hand-authored piece of map.
+#[derive(Clone, Debug)]
+pub struct Prefab {
+  name: String,
+  kind: RoomKind,
+  dims: Point,
+  /// The contents of each point, in row-major order; `None` means that the
+  /// point is left alone.
+  cells: Vec<Option<(Tile, Option<Spawn>)>>,
+}
+
+/// A rotation and reflection to apply to a [`Prefab`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Transform {
+  /// Whether to mirror the prefab left-to-right; this happens before rotating.
+  pub flip: bool,
+  /// How many quarter turns clockwise to rotate the prefab by.
+  pub turns: u8,
+}
+
+impl Transform {
+  /// Picks a random `Transform`.
+  pub fn random(rng: &mut impl Rng) -> Self {
+    Self {
+      flip: rng.gen(),
+      turns: rng.gen_range(0..4),
+    }
+  }
+}
+
+impl Prefab {
+  /// Returns this prefab's name.
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Returns the kind of room this prefab is.
+  pub fn kind(&self) -> RoomKind {
+    self.kind
+  }
+
+  /// Returns the width and height of this prefab once `transform` is applied.
+  pub fn dims(&self, transform: Transform) -> Point {
+    match transform.turns % 2 {
+      0 => self.dims,
+      _ => Point::new(self.dims.y(), self.dims.x()),
+    }
+  }
+
+  /// Returns every non-empty cell of this prefab with `transform` applied,
+  /// relative to its upper-left corner.
+  fn cells(
+    &self,
+    transform: Transform,
+  ) -> impl Iterator<Item = (Point, Tile, Option<Spawn>)> + '_ {
+    self.cells.iter().enumerate().filter_map(move |(i, cell)| {
+      let (tile, spawn) = (*cell)?;
+      let (mut w, mut h) = (self.dims.x(), self.dims.y());
+      let (mut x, mut y) = (i as i64 % w, i as i64 / w);
+      if transform.flip {
+        x = w - 1 - x;
+      }
+      for _ in 0..transform.turns % 4 {
+        let (x2, y2) = (h - 1 - y, x);
+        x = x2;
+        y = y2;
+        mem::swap(&mut w, &mut h);
+      }
+      Some((Point::new(x, y), tile, spawn))
+    })
+  }
+}
+
+/// A legend, mapping characters to what they stand for.
+type Legend = HashMap<char, (Tile, Option<Spawn>)>;
+
+/// A prefab that is still being parsed.
+struct Pending<'a> {
+  name: String,
+  kind: RoomKind,
+  legend: Legend,
+  /// Each line of the map, along with its line number.
+  rows: Vec<(usize, &'a str)>,
+}
+
+impl Pending<'_> {
+  /// Builds the prefab, now that its map has been read.
+  fn finish(self) -> Result<Prefab, ParseError> {
+    let Self {
+      name,
+      kind,
+      legend,
+      rows,
+    } = self;
+    let width = match rows.first() {
+      Some((_, row)) => row.chars().count(),
+      None => {
+        return Err(ParseError {
+          line: 0,
+          message: format!("prefab `{}` has no map", name),
+        })
+      }
+    };
+
+    let mut cells = Vec::with_capacity(width * rows.len());
+    for &(line, row) in &rows {
+      let row_width = row.chars().count();
+      if row_width != width {
+        return Err(ParseError {
+          line,
+          message: format!(
+            "row is {} wide, but the first row is {} wide",
+            row_width, width
+          ),
+        });
+      }
+      for c in row.chars() {
+        let cell = match c {
+          ' ' => None,
+          c => Some(*legend.get(&c).ok_or_else(|| ParseError {
+            line,
+            message: format!("`{}` is not in the legend", c),
+          })?),
+        };
+        cells.push(cell);
+      }
+    }
+
+    Ok(Prefab {
+      name,
+      kind,
+      dims: Point::new(width as i64, rows.len() as i64),
+      cells,
+    })
+  }
+}
+
+/// Resource: A registry of [`Prefab`]s.
+#[derive(Clone, Debug)]
+pub struct Prefabs {
+  prefabs: Vec<Prefab>,
+}
+
+impl Prefabs {
+  /// Returns the prefabs built into the game.
+  pub fn builtin() -> Self {
+    Self::parse(include_str!("../../data/prefabs.txt"))
+      .expect("built-in prefabs should be valid")
+  }
+
+  /// Loads prefabs from the file at `path`.
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, ParseError> {
+    let src = fs::read_to_string(path).map_err(|e| ParseError {
+      line: 0,
+      message: e.to_string(),
+    })?;
+    Self::parse(&src)
+  }
+
+  /// Parses prefabs from `src`.
+  pub fn parse(src: &str) -> Result<Self, ParseError> {
+    let mut shared = Legend::new();
+    let mut prefabs = Vec::new();
+    let mut current: Option<Pending> = None;
+    let mut in_map = false;
+
+    for (i, line) in src.lines().enumerate() {
+      let err = |message: String| ParseError {
+        line: i + 1,
+        message,
+      };
+
+      if in_map {
+        // Trailing spaces are part of the map, so rows are left untrimmed.
+        if !line.trim().is_empty() {
+          current.as_mut().unwrap().rows.push((i + 1, line));
+          continue;
+        }
+        in_map = false;
+      }
+
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      if let Some(name) =
+        line.strip_prefix('[').and_then(|l| l.strip_suffix(']'))
+      {
+        let name = name.trim();
+        if name.is_empty() {
+          return Err(err("prefab has no name".into()));
+        }
+        if prefabs.iter().any(|p: &Prefab| p.name == name)
+          || current.iter().any(|p| p.name == name)
+        {
+          return Err(err(format!("prefab `{}` defined twice", name)));
+        }
+        if let Some(prefab) = current.take() {
+          prefabs.push(prefab.finish()?);
+        }
+        current = Some(Pending {
+          name: name.to_string(),
+          kind: RoomKind::Vault,
+          legend: shared.clone(),
+          rows: Vec::new(),
+        });
+        continue;
+      }
+
+      if line == "map:" {
+        match &current {
+          Some(p) if p.rows.is_empty() => in_map = true,
+          Some(_) => return Err(err("prefab has two maps".into())),
+          None => return Err(err("expected a `[prefab]`".into())),
+        }
+        continue;
+      }
+
+      // The legend character may itself be `=`, so skip past it before
+      // looking for the separator.
+      let skip = match line.starts_with(&['\'', '"'][..]) {
+        true => line.char_indices().nth(2).map_or(line.len(), |(i, _)| i),
+        false => 0,
+      };
+      let (key, value) = match line[skip..].find('=') {
+        Some(idx) => {
+          let idx = skip + idx;
+          (line[..idx].trim(), line[idx + 1..].trim())
+        }
+        None => return Err(err("expected `'c' = tile`".into())),
+      };
+
+      if key == "kind" {
+        let kind = RoomKind::from_name(value)
+          .ok_or_else(|| err(format!("unknown room kind `{}`", value)))?;
+        match &mut current {
+          Some(p) => p.kind = kind,
+          None => return Err(err("expected a `[prefab]`".into())),
+        }
+        continue;
+      }
+
+      let key = unquote(key);
+      let mut chars = key.chars();
+      let c = match (chars.next(), chars.next()) {
+        (Some(c), None) if c != ' ' => c,
+        _ => return Err(err(format!("invalid legend character `{}`", key))),
+      };
+
+      let mut words = value.split_whitespace();
+      let tile = words.next().unwrap_or("");
+      let tile = Tile::from_name(tile)
+        .ok_or_else(|| err(format!("unknown tile `{}`", tile)))?;
+      let spawn = match words.next() {
+        Some(name) => Some(
+          Spawn::from_name(name)
+            .ok_or_else(|| err(format!("unknown spawn `{}`", name)))?,
+        ),
+        None => None,
+      };
+      if let Some(extra) = words.next() {
+        return Err(err(format!("unexpected `{}`", extra)));
+      }
+
+      match &mut current {
+        Some(p) => p.legend.insert(c, (tile, spawn)),
+        None => shared.insert(c, (tile, spawn)),
+      };
+    }
+
+    if let Some(prefab) = current.take() {
+      prefabs.push(prefab.finish()?);
+    }
+    Ok(Self { prefabs })
+  }
+
+  /// Returns every prefab in this registry.
+  pub fn all(&self) -> &[Prefab] {
+    &self.prefabs
+  }
+
+  /// Looks up a prefab by name.
+  pub fn get(&self, name: &str) -> Option<&Prefab> {
+    self.prefabs.iter().find(|p| p.name == name)
+  }
+
+  /// Picks a random prefab and [`Transform`] no larger than `max`, if there
+  /// is one.
+  pub fn pick(
+    &self,
+    rng: &mut impl Rng,
+    max: Point,
+  ) -> Option<(&Prefab, Transform)> {
+    let transform = Transform::random(rng);
+    let fits = self
+      .prefabs
+      .iter()
+      .filter(|p| {
+        let dims = p.dims(transform);
+        dims.x() <= max.x() && dims.y() <= max.y()
+      })
+      .collect::<Vec<_>>();
+    fits.choose(rng).map(|&p| (p, transform))
+  }
+}
+
+impl Floor {
+  /// Stamps `prefab` into this floor with its upper-left corner at `pos`.
+  ///
+  /// Unlike with [`Floor::add_room()`], the prefab's tiles replace whatever
+  /// was there before, except where the prefab leaves things alone. Anything
+  /// the prefab spawns is queued up; see [`Floor::take_spawns()`].
+  ///
+  /// Returns the space taken up by the prefab, which callers should record as
+  /// a room in order to connect it to the rest of the floor.
+  pub fn add_prefab(
+    &mut self,
+    prefab: &Prefab,
+    transform: Transform,
+    pos: Point,
+  ) -> Rect {
+    for (p, tile, spawn) in prefab.cells(transform) {
+      let p = p + pos;
+      *self.chunk_mut(p).tile_mut(p) = tile;
+      if let Some(spawn) = spawn {
+        self.spawns.push((p, spawn));
+      }
+    }
+
+    let dims = prefab.dims(transform);
+    Rect::with_dims(dims.x(), dims.y()) + pos
+  }
+
+  /// Returns everything that generators have asked to be spawned on this
+  /// floor, and clears the queue.
+  pub fn take_spawns(&mut self) -> Vec<(Point, Spawn)> {
+    mem::take(&mut self.spawns)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An asymmetric prefab, in which every cell is different.
+  const ASYMMETRIC: &str = "
+'#' = wall
+'.' = ground
+
+[lopsided]
+kind = den
+'K' = ground monster
+'^' = trap_hidden
+'+' = door_closed
+map:
+#K^
+ .+
+";
+
+  /// Draws `prefab` with `transform` applied, using the legend of
+  /// [`ASYMMETRIC`].
+  fn draw(prefab: &Prefab, transform: Transform) -> Vec<String> {
+    let dims = prefab.dims(transform);
+    let mut rows = vec![vec![' '; dims.x() as usize]; dims.y() as usize];
+    for (p, tile, spawn) in prefab.cells(transform) {
+      rows[p.y() as usize][p.x() as usize] = match (tile, spawn) {
+        (Tile::Wall, None) => '#',
+        (Tile::Ground, None) => '.',
+        (Tile::Ground, Some(Spawn::Monster)) => 'K',
+        (Tile::TrapHidden, None) => '^',
+        (Tile::DoorClosed, None) => '+',
+        cell => panic!("unexpected cell {:?}", cell),
+      };
+    }
+    rows.into_iter().map(|r| r.into_iter().collect()).collect()
+  }
+
+  fn parse_err(src: &str) -> ParseError {
+    match Prefabs::parse(src) {
+      Ok(_) => panic!("parsed successfully:\n{}", src),
+      Err(e) => e,
+    }
+  }
+
+  #[test]
+  fn builtin_prefabs_parse() {
+    assert!(!Prefabs::builtin().all().is_empty());
+  }
+
+  #[test]
+  fn parse() {
+    let prefabs = Prefabs::parse(ASYMMETRIC).unwrap();
+    assert_eq!(prefabs.all().len(), 1);
+    let prefab = prefabs.get("lopsided").unwrap();
+    assert_eq!(prefab.name(), "lopsided");
+    assert_eq!(prefab.kind(), RoomKind::Den);
+    assert_eq!(prefab.dims(Transform::default()), Point::new(3, 2));
+    assert_eq!(draw(prefab, Transform::default()), vec!["#K^", " .+"]);
+
+    // Prefabs are vaults unless they say otherwise, and the legend given
+    // before the first prefab is shared by all of them.
+    let prefabs = Prefabs::parse(
+      "'#' = wall\n[a]\nmap:\n#\n\n[b]\n'#' = ground\nmap:\n#\n",
+    )
+    .unwrap();
+    let (a, b) = (prefabs.get("a").unwrap(), prefabs.get("b").unwrap());
+    assert_eq!(a.kind(), RoomKind::Vault);
+    let cell = |p: &Prefab| p.cells(Transform::default()).next().unwrap();
+    assert_eq!(cell(a), (Point::zero(), Tile::Wall, None));
+    assert_eq!(cell(b), (Point::zero(), Tile::Ground, None));
+  }
+
+  #[test]
+  fn transforms() {
+    let prefabs = Prefabs::parse(ASYMMETRIC).unwrap();
+    let prefab = prefabs.get("lopsided").unwrap();
+    let expected: [(bool, u8, &[&str]); 8] = [
+      (false, 0, &["#K^", " .+"]),
+      (false, 1, &[" #", ".K", "+^"]),
+      (false, 2, &["+. ", "^K#"]),
+      (false, 3, &["^+", "K.", "# "]),
+      (true, 0, &["^K#", "+. "]),
+      (true, 1, &["+^", ".K", " #"]),
+      (true, 2, &[" .+", "#K^"]),
+      (true, 3, &["# ", "K.", "^+"]),
+    ];
+
+    for &(flip, turns, rows) in &expected {
+      let transform = Transform { flip, turns };
+      assert_eq!(draw(prefab, transform), rows, "{:?}", transform);
+
+      // Stamping the prefab puts everything in the same places, offset by
+      // where it was stamped.
+      let pos = Point::new(-7, 12);
+      let mut floor = Floor::new();
+      let rect = floor.add_prefab(prefab, transform, pos);
+      let dims = prefab.dims(transform);
+      assert_eq!(rect, Rect::with_dims(dims.x(), dims.y()) + pos);
+
+      let mut monster = None;
+      for (y, row) in rows.iter().enumerate() {
+        for (x, c) in row.chars().enumerate() {
+          let p = pos + Point::new(x as i64, y as i64);
+          let tile = match c {
+            '#' => Tile::Wall,
+            '^' => Tile::TrapHidden,
+            '+' => Tile::DoorClosed,
+            ' ' => Tile::Void,
+            _ => Tile::Ground,
+          };
+          assert_eq!(floor.tile(p), tile, "{:?} at {:?}", transform, p);
+          if c == 'K' {
+            monster = Some(p);
+          }
+        }
+      }
+      assert_eq!(
+        floor.take_spawns(),
+        vec![(monster.unwrap(), Spawn::Monster)]
+      );
+    }
+  }
+
+  #[test]
+  fn bad_prefabs_are_rejected() {
+    let e = parse_err("'#' = wall\n[a]\nmap:\n###\n#?#\n");
+    assert_eq!(e.line, 5);
+    assert_eq!(e.message, "`?` is not in the legend");
+
+    let e = parse_err("'#' = wall\n[a]\nmap:\n###\n##\n###\n");
+    assert_eq!(e.line, 5);
+    assert_eq!(e.message, "row is 2 wide, but the first row is 3 wide");
+    // Trailing spaces count towards a row's width.
+    let e = parse_err("'#' = wall\n[a]\nmap:\n# \n#\n");
+    assert_eq!(e.line, 5);
+    assert!(Prefabs::parse("'#' = wall\n[a]\nmap:\n# \n##\n").is_ok());
+
+    for header in &["[]", "[  ]"] {
+      let e = parse_err(&format!("'#' = wall\n{}\nmap:\n#\n", header));
+      assert_eq!(e.line, 2);
+      assert_eq!(e.message, "prefab has no name");
+    }
+
+    let e = parse_err("'#' = wall\n[a]\nmap:\n#\n\n[a]\nmap:\n#\n");
+    assert_eq!(e.line, 6);
+    assert_eq!(e.message, "prefab `a` defined twice");
+    let e = parse_err("[a]\n'#' = wall\n");
+    assert_eq!(e.message, "prefab `a` has no map");
+  }
+}
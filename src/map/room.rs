@@ -0,0 +1,256 @@
+//! Rooms, and what they are for.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use rand::seq::SliceRandom as _;
+use rand::Rng;
+
+use crate::geo::graph;
+use crate::geo::Dir;
+use crate::geo::Point;
+use crate::geo::Rect;
+use crate::map::Floor;
+use crate::map::Tiles;
+use crate::save;
+use crate::save::Decode;
+use crate::save::Encode;
+
+macro_rules! room_kinds {
+  ($($(#[$doc:meta])* $variant:ident => $name:literal,)*) => {
+    /// What a [`Room`] is for, which decides how it is populated and
+    /// decorated.
+    #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+    pub enum RoomKind {
+      $($(#[$doc])* $variant,)*
+    }
+
+    impl RoomKind {
+      /// Returns the name this kind is referred to by in data files.
+      pub fn name(self) -> &'static str {
+        match self {
+          $(RoomKind::$variant => $name,)*
+        }
+      }
+
+      /// Looks up a kind by the name it is referred to by in data files.
+      pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+          $($name => Some(RoomKind::$variant),)*
+          _ => None,
+        }
+      }
+    }
+  };
+}
+
+room_kinds! {
+  /// An ordinary room.
+  Plain => "plain",
+  /// An open area of a cave.
+  Cavern => "cavern",
+  /// A hand-authored prefab with no more specific purpose.
+  Vault => "vault",
+  /// A dead end where treasure is kept, under guard.
+  Treasury => "treasury",
+  /// A shrine, which is left unguarded.
+  Shrine => "shrine",
+  /// A nest of monsters.
+  Den => "den",
+}
+
+impl RoomKind {
+  /// Returns how many monsters to spawn in a room of this kind, on top of
+  /// anything the room's generator spawned itself.
+  pub fn monsters(self) -> usize {
+    match self {
+      RoomKind::Plain | RoomKind::Cavern | RoomKind::Treasury => 1,
+      RoomKind::Vault | RoomKind::Shrine => 0,
+      RoomKind::Den => 3,
+    }
+  }
+}
+
+/// A room on a [`Floor`].
+#[derive(Clone, PartialEq, Debug)]
+pub struct Room {
+  /// The space the room takes up, including its walls. Rooms need not fill
+  /// all of it, but the center is always open.
+  pub rect: Rect,
+  /// What the room is for.
+  pub kind: RoomKind,
+  /// The doors in the room's walls; see [`Floor::place_doors()`].
+  pub doors: Vec<Point>,
+  /// The indices of the rooms that can be walked to from this one without
+  /// passing through any other room; see [`Floor::link_rooms()`].
+  pub neighbors: Vec<usize>,
+}
+
+impl Room {
+  /// Creates a new `Room` with no doors or neighbors.
+  pub fn new(rect: Rect, kind: RoomKind) -> Self {
+    Self {
+      rect,
+      kind,
+      doors: Vec::new(),
+      neighbors: Vec::new(),
+    }
+  }
+
+  /// Returns the center of this room.
+  pub fn center(&self) -> Point {
+    self.rect.center()
+  }
+}
+
+/// The chance that an ordinary room is given each of the special kinds by
+/// [`Floor::theme_rooms()`].
+const THEME_CHANCE: f64 = 0.1;
+
+impl Floor {
+  /// Joins the rooms with corridors along a minimum spanning tree of their
+  /// centers, so that every room is reachable from every other, plus some
+  /// extra corridors that form loops.
+  ///
+  /// The candidate corridors are the edges of the [Gabriel graph] of the room
+  /// centers, so corridors only ever run between rooms that are near each
+  /// other. `loops` is the fraction of the candidates that are not needed for
+  /// the spanning tree, but are carved anyway.
+  ///
+  /// [Gabriel graph]: crate::geo::graph::gabriel_graph
+  pub fn connect_room_graph(&mut self, rng: &mut impl Rng, loops: f64) {
+    let centers = self.rooms.iter().map(|r| r.center()).collect::<Vec<_>>();
+    let edges = graph::gabriel_graph(&centers);
+    let tree = graph::spanning_tree(centers.len(), &edges, |a, b| {
+      let d = centers[a] - centers[b];
+      ((d.x() * d.x() + d.y() * d.y()) as f64).sqrt()
+    });
+
+    let mut extra = edges
+      .into_iter()
+      .filter(|e| !tree.contains(e))
+      .collect::<Vec<_>>();
+    extra.shuffle(rng);
+    extra.truncate((extra.len() as f64 * loops).round() as usize);
+
+    let zones = self.zones();
+    for (a, b) in tree.into_iter().chain(extra) {
+      self.add_corridor(&zones, centers[a], centers[b]);
+    }
+    self.place_doors();
+  }
+
+  /// Works out which rooms are connected to which, filling in each room's
+  /// [`Room::neighbors`].
+  ///
+  /// The walkable tiles outside of rooms are divided up between the rooms by
+  /// flood-filling outwards from all of them at once; wherever two rooms'
+  /// fills meet, those rooms are neighbors.
+  pub fn link_rooms(&mut self, tiles: &Tiles) {
+    let is_passable = |p| tiles.get(self.tile(p)).is_passable();
+
+    let mut owners = HashMap::new();
+    let mut queue = VecDeque::new();
+    for (i, room) in self.rooms.iter().enumerate() {
+      for p in room.rect.points() {
+        if is_passable(p) && !owners.contains_key(&p) {
+          owners.insert(p, i);
+          queue.push_back(p);
+        }
+      }
+    }
+
+    let mut links = HashSet::new();
+    while let Some(p) = queue.pop_front() {
+      let i = owners[&p];
+      for &d in &Dir::all() {
+        let n = p + d.to_point::<i64>();
+        if !is_passable(n) {
+          continue;
+        }
+        match owners.get(&n) {
+          Some(&j) if j != i => {
+            links.insert((i.min(j), i.max(j)));
+          }
+          Some(_) => {}
+          None => {
+            owners.insert(n, i);
+            queue.push_back(n);
+          }
+        }
+      }
+    }
+
+    for room in &mut self.rooms {
+      room.neighbors.clear();
+    }
+    let mut links = links.into_iter().collect::<Vec<_>>();
+    links.sort_unstable();
+    for (i, j) in links {
+      self.rooms[i].neighbors.push(j);
+      self.rooms[j].neighbors.push(i);
+    }
+  }
+
+  /// Gives some of the ordinary rooms a special kind at random.
+  ///
+  /// The first room, which is where the player arrives, is left alone, and
+  /// only dead ends become treasuries. This should be called after
+  /// [`Floor::link_rooms()`].
+  pub fn theme_rooms(&mut self, rng: &mut impl Rng) {
+    for room in self.rooms.iter_mut().skip(1) {
+      if room.kind != RoomKind::Plain {
+        continue;
+      }
+
+      let roll: f64 = rng.gen();
+      room.kind = if roll < THEME_CHANCE && room.neighbors.len() == 1 {
+        RoomKind::Treasury
+      } else if roll < THEME_CHANCE * 2.0 {
+        RoomKind::Shrine
+      } else if roll < THEME_CHANCE * 3.0 {
+        RoomKind::Den
+      } else {
+        RoomKind::Plain
+      };
+    }
+  }
+}
+
+impl Encode for RoomKind {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.name().encode(w)
+  }
+}
+
+impl Decode for RoomKind {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let name = String::decode(r)?;
+    RoomKind::from_name(&name)
+      .ok_or_else(|| save::invalid(format!("unknown room kind `{}`", name)))
+  }
+}
+
+impl Encode for Room {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.rect.encode(w)?;
+    self.kind.encode(w)?;
+    self.doors.encode(w)?;
+    self.neighbors.encode(w)
+  }
+}
+
+impl Decode for Room {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    Ok(Self {
+      rect: Rect::decode(r)?,
+      kind: RoomKind::decode(r)?,
+      doors: Vec::decode(r)?,
+      neighbors: Vec::decode(r)?,
+    })
+  }
+}
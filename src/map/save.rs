@@ -0,0 +1,329 @@
+//! Saving and loading floors.
+//!
+//! A saved [`Floor`] starts with a `FLOR` header and a version number,
+//! followed by a table of tile names. Tiles are stored as indices into that
+//! table rather than by their position in [`Tile::ALL`], so that adding new
+//! tiles doesn't invalidate old saves.
+//!
+//! Each chunk's tiles are then stored as a sequence of runs of identical
+//! tiles; since most chunks are largely `Void`, this keeps saves small.
+//!
+//! Version 1 saves stored only the outline of each room; rooms loaded from
+//! them are [`RoomKind::Plain`], with no doors or neighbors recorded.
+//!
+//! A [`Memory`] is saved the same way, minus the header and rooms, followed by
+//! the set of points that have been seen.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use crate::geo::Point;
+use crate::geo::PointSet;
+use crate::geo::Rect;
+use crate::map::normalize;
+use crate::map::Chunk;
+use crate::map::Floor;
+use crate::map::Memory;
+use crate::map::Room;
+use crate::map::RoomKind;
+use crate::map::Tile;
+use crate::map::WIDTH;
+use crate::save;
+use crate::save::Decode;
+use crate::save::Encode;
+
+const MAGIC: &[u8; 4] = b"FLOR";
+const VERSION: u32 = 2;
+
+impl Encode for Floor {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    save::write_header(w, MAGIC, VERSION)?;
+    encode_palette(w)?;
+    encode_chunks(w, &self.chunks)?;
+    self.rooms.encode(w)
+  }
+}
+
+impl Decode for Floor {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let version = save::expect_header(r, MAGIC, VERSION)?;
+    let palette = decode_palette(r)?;
+    let chunks = decode_chunks(r, &palette)?;
+
+    let rooms = match version {
+      1 => Vec::<Rect>::decode(r)?
+        .into_iter()
+        .map(|rect| Room::new(rect, RoomKind::Plain))
+        .collect(),
+      _ => Vec::<Room>::decode(r)?,
+    };
+    for room in &rooms {
+      if let Some(&i) = room.neighbors.iter().find(|&&i| i >= rooms.len()) {
+        return Err(save::invalid(format!("room {} is out of range", i)));
+      }
+    }
+
+    Ok(Floor {
+      chunks,
+      rooms,
+      spawns: Vec::new(),
+      revision: 0,
+    })
+  }
+}
+
+impl Encode for Memory {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    encode_palette(w)?;
+    encode_chunks(w, &self.chunks)?;
+    self.seen.encode(w)
+  }
+}
+
+impl Decode for Memory {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let palette = decode_palette(r)?;
+    let chunks = decode_chunks(r, &palette)?;
+    let seen = PointSet::decode(r)?;
+    if let Some(p) = seen.iter().find(|&p| !chunks.contains_key(&normalize(p)))
+    {
+      return Err(save::invalid(format!("no chunk for seen point {:?}", p)));
+    }
+    Ok(Memory { seen, chunks })
+  }
+}
+
+/// Writes the names of every tile, in the order [`Chunk::encode()`] numbers
+/// them.
+fn encode_palette(w: &mut dyn Write) -> io::Result<()> {
+  Tile::ALL.len().encode(w)?;
+  for tile in Tile::ALL {
+    tile.name().encode(w)?;
+  }
+  Ok(())
+}
+
+/// Reads a table of tile names written by [`encode_palette()`].
+fn decode_palette(r: &mut dyn Read) -> io::Result<Vec<Tile>> {
+  Vec::<String>::decode(r)?
+    .iter()
+    .map(|name| {
+      Tile::from_name(name)
+        .ok_or_else(|| save::invalid(format!("unknown tile `{}`", name)))
+    })
+    .collect()
+}
+
+/// Writes every chunk in `chunks`, sorted so that the same chunks always save
+/// the same way.
+fn encode_chunks(
+  w: &mut dyn Write,
+  chunks: &HashMap<Point, Chunk>,
+) -> io::Result<()> {
+  let mut chunks = chunks.values().collect::<Vec<_>>();
+  chunks.sort_by_key(|c| (c.pos.y(), c.pos.x()));
+  chunks.len().encode(w)?;
+  for chunk in chunks {
+    chunk.encode(w)?;
+  }
+  Ok(())
+}
+
+/// Reads chunks written by [`encode_chunks()`], using `palette` to map the
+/// saved tile numbers to tiles.
+fn decode_chunks(
+  r: &mut dyn Read,
+  palette: &[Tile],
+) -> io::Result<HashMap<Point, Chunk>> {
+  let len = usize::decode(r)?;
+  let mut chunks = HashMap::new();
+  for _ in 0..len {
+    let chunk = Chunk::decode_with(r, palette)?;
+    if chunks.contains_key(&chunk.pos) {
+      return Err(save::invalid(format!("duplicate chunk at {:?}", chunk.pos)));
+    }
+    chunks.insert(chunk.pos, chunk);
+  }
+  Ok(chunks)
+}
+
+impl Encode for Chunk {
+  /// Writes this chunk's position and run-length encoded tiles.
+  ///
+  /// Tiles are written by their position in [`Tile::ALL`]; use
+  /// [`Chunk::decode_with()`] to read them back with a different numbering.
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.pos.encode(w)?;
+
+    let mut tiles = self.tiles.iter().peekable();
+    while let Some(&tile) = tiles.next() {
+      let mut len = 1u16;
+      while tiles.next_if_eq(&&tile).is_some() {
+        len += 1;
+      }
+      (tile as u8).encode(w)?;
+      len.encode(w)?;
+    }
+    Ok(())
+  }
+}
+
+impl Decode for Chunk {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    Self::decode_with(r, Tile::ALL)
+  }
+}
+
+impl Chunk {
+  /// Reads a chunk written by [`Chunk::encode()`], using `palette` to map the
+  /// saved tile numbers to tiles.
+  fn decode_with(r: &mut dyn Read, palette: &[Tile]) -> io::Result<Self> {
+    let pos = Point::decode(r)?;
+    if normalize(pos) != pos {
+      return Err(save::invalid(format!(
+        "chunk position {:?} is not a multiple of {}",
+        pos, WIDTH
+      )));
+    }
+
+    let mut chunk = Chunk::new(pos);
+    let mut filled = 0;
+    while filled < WIDTH * WIDTH {
+      let idx = u8::decode(r)?;
+      let tile = *palette.get(idx as usize).ok_or_else(|| {
+        save::invalid(format!("tile number {} is out of range", idx))
+      })?;
+
+      let len = u16::decode(r)? as usize;
+      if len == 0 || filled + len > WIDTH * WIDTH {
+        return Err(save::invalid(format!("invalid run length {}", len)));
+      }
+      for slot in &mut chunk.tiles[filled..filled + len] {
+        *slot = tile;
+      }
+      filled += len;
+    }
+    Ok(chunk)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Builds a small floor spanning chunks on both sides of the origin, with a
+  /// couple of rooms.
+  fn sample_floor() -> Floor {
+    let mut floor = Floor::new();
+    let a = Rect::new(Point::new(-40, -70), Point::new(-25, -58));
+    let b = Rect::new(Point::new(-5, -4), Point::new(6, 3));
+    floor.add_room(a);
+    floor.add_room(b);
+    for p in Rect::new(Point::new(-25, -64), Point::new(-5, -64)).points() {
+      *floor.chunk_mut(p).tile_mut(p) = Tile::Ground;
+    }
+    let p = Point::new(-25, -64);
+    *floor.chunk_mut(p).tile_mut(p) = Tile::DoorClosed;
+
+    let mut room = Room::new(a, RoomKind::Den);
+    room.doors.push(p);
+    room.neighbors.push(1);
+    floor.rooms.push(room);
+    floor.rooms.push(Room::new(b, RoomKind::Plain));
+    floor
+  }
+
+  fn assert_same_tiles(a: &Floor, b: &Floor) {
+    let mut positions = a.chunks.keys().collect::<Vec<_>>();
+    positions.sort_by_key(|p| (p.y(), p.x()));
+    let mut other = b.chunks.keys().collect::<Vec<_>>();
+    other.sort_by_key(|p| (p.y(), p.x()));
+    assert_eq!(positions, other);
+
+    for pos in positions {
+      assert_eq!(a.chunks[pos].tiles[..], b.chunks[pos].tiles[..]);
+    }
+  }
+
+  #[test]
+  fn floor_round_trip() {
+    let floor = sample_floor();
+    assert!(floor.chunk(Point::new(-32, -64)).is_some());
+
+    let mut buf = Vec::new();
+    floor.encode(&mut buf).unwrap();
+    let decoded = Floor::decode(&mut buf.as_slice()).unwrap();
+
+    assert_same_tiles(&floor, &decoded);
+    assert_eq!(floor.rooms, decoded.rooms);
+  }
+
+  #[test]
+  fn chunk_round_trip() {
+    let pos = Point::new(-32, -64);
+    let mut chunk = Chunk::new(pos);
+    *chunk.tile_mut(pos) = Tile::Wall;
+    *chunk.tile_mut(pos + Point::new(31, 31)) = Tile::StairsDown;
+    for x in 0..WIDTH as i64 {
+      *chunk.tile_mut(pos + Point::new(x, 10)) = Tile::Ground;
+    }
+
+    let mut buf = Vec::new();
+    chunk.encode(&mut buf).unwrap();
+    let decoded = Chunk::decode(&mut buf.as_slice()).unwrap();
+    assert_eq!(decoded.pos, pos);
+    assert_eq!(decoded.tiles[..], chunk.tiles[..]);
+  }
+
+  #[test]
+  fn memory_round_trip() {
+    let mut memory = Memory::new();
+    memory.insert(Point::new(-32, -64), Tile::Wall);
+    memory.insert(Point::new(-1, -1), Tile::DoorOpen);
+    memory.insert(Point::new(5, 40), Tile::Void);
+
+    let mut buf = Vec::new();
+    memory.encode(&mut buf).unwrap();
+    let decoded = Memory::decode(&mut buf.as_slice()).unwrap();
+
+    let mut expected = memory.iter().collect::<Vec<_>>();
+    let mut actual = decoded.iter().collect::<Vec<_>>();
+    expected.sort_by_key(|&(p, _)| (p.y(), p.x()));
+    actual.sort_by_key(|&(p, _)| (p.y(), p.x()));
+    assert_eq!(actual, expected);
+    assert_eq!(decoded.get(Point::new(5, 40)), Some(Tile::Void));
+    assert_eq!(decoded.get(Point::new(5, 41)), None);
+  }
+
+  #[test]
+  fn misaligned_chunk_is_rejected() {
+    let mut buf = Vec::new();
+    Chunk::new(Point::new(-32, -64)).encode(&mut buf).unwrap();
+    buf[..8].copy_from_slice(&(-31i64).to_le_bytes());
+    assert!(Chunk::decode(&mut buf.as_slice()).is_err());
+  }
+
+  #[test]
+  fn version_1_floors_are_upgraded() {
+    let floor = sample_floor();
+
+    // Version 1 saves are laid out the same way, except that only the rooms'
+    // outlines are stored.
+    let mut buf = Vec::new();
+    save::write_header(&mut buf, MAGIC, 1).unwrap();
+    encode_palette(&mut buf).unwrap();
+    encode_chunks(&mut buf, &floor.chunks).unwrap();
+    let rects = floor.rooms.iter().map(|r| r.rect).collect::<Vec<_>>();
+    rects.encode(&mut buf).unwrap();
+
+    let decoded = Floor::decode(&mut buf.as_slice()).unwrap();
+    assert_same_tiles(&floor, &decoded);
+    let expected = rects
+      .into_iter()
+      .map(|rect| Room::new(rect, RoomKind::Plain))
+      .collect::<Vec<_>>();
+    assert_eq!(decoded.rooms, expected);
+  }
+}
@@ -0,0 +1,89 @@
+//! Hiding secret doors, traps and caches around a floor.
+
+use rand::seq::SliceRandom as _;
+use rand::Rng;
+
+use crate::map::Floor;
+use crate::map::RoomKind;
+use crate::map::Tile;
+use crate::map::Tiles;
+
+/// The chance that a room has one of its doors hidden.
+const SECRET_DOOR_CHANCE: f64 = 0.2;
+
+/// The chance that an ordinary room or cavern has a trap in it.
+const TRAP_CHANCE: f64 = 0.25;
+
+/// The chance that an ordinary room or cavern has a hidden cache in it.
+const CACHE_CHANCE: f64 = 0.15;
+
+impl Floor {
+  /// Hides secret doors, traps and caches around the rooms of this floor.
+  ///
+  /// A door is only hidden if every room that could be reached before can
+  /// still be reached without it, so that secret doors only ever hide
+  /// shortcuts. Treasuries keep their gold out in the open, but guarded by
+  /// traps, and dens are trapped too. The first room is left alone, as is the
+  /// center of every room, which is where stairs and monsters go.
+  ///
+  /// This should be called after [`Floor::theme_rooms()`].
+  pub fn add_secrets(&mut self, rng: &mut impl Rng, tiles: &Tiles) {
+    let start = match self.rooms.first() {
+      Some(room) => room.center(),
+      None => return,
+    };
+    let reachable = |floor: &Floor| {
+      let reached = floor.reachable_from(tiles, start);
+      floor
+        .rooms
+        .iter()
+        .filter(|r| reached.contains(&r.center()))
+        .count()
+    };
+    let connected = reachable(self);
+
+    for i in 1..self.rooms.len() {
+      let room = &self.rooms[i];
+      let (rect, center) = (room.rect, room.center());
+
+      let doors = room
+        .doors
+        .iter()
+        .copied()
+        .filter(|&p| self.tile(p) == Tile::DoorClosed)
+        .collect::<Vec<_>>();
+      if rng.gen_bool(SECRET_DOOR_CHANCE) {
+        if let Some(&p) = doors.choose(rng) {
+          *self.chunk_mut(p).tile_mut(p) = Tile::SecretDoor;
+          if reachable(self) < connected {
+            *self.chunk_mut(p).tile_mut(p) = Tile::DoorClosed;
+          }
+        }
+      }
+
+      let (traps, cache) = match self.rooms[i].kind {
+        RoomKind::Treasury => (2, Some(Tile::Cache)),
+        RoomKind::Den => (2, None),
+        RoomKind::Plain | RoomKind::Cavern => (
+          rng.gen_bool(TRAP_CHANCE) as usize,
+          Some(Tile::CacheHidden).filter(|_| rng.gen_bool(CACHE_CHANCE)),
+        ),
+        _ => (0, None),
+      };
+
+      let spots = rect
+        .points()
+        .filter(|&p| p != center && self.tile(p) == Tile::Ground)
+        .collect::<Vec<_>>();
+      let mut spots = spots
+        .choose_multiple(rng, traps + cache.iter().count())
+        .copied();
+      for p in spots.by_ref().take(traps) {
+        *self.chunk_mut(p).tile_mut(p) = Tile::TrapHidden;
+      }
+      if let (Some(tile), Some(p)) = (cache, spots.next()) {
+        *self.chunk_mut(p).tile_mut(p) = tile;
+      }
+    }
+  }
+}
@@ -0,0 +1,256 @@
+//! Streaming endless floors.
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use legion::query::component;
+use legion::world::SubWorld;
+use legion::IntoQuery as _;
+
+use crate::actor::base::HasCamera;
+use crate::actor::base::Position;
+use crate::geo::Point;
+use crate::geo::Rect;
+use crate::gfx::curses;
+use crate::map::normalize;
+use crate::map::Chunk;
+use crate::map::Floor;
+use crate::map::WIDTH;
+use crate::save::Decode;
+use crate::save::Encode;
+use crate::timing::SystemTimer;
+
+/// A generator for endless floors, which fills in one chunk at a time.
+///
+/// Chunks are generated in whatever order the player happens to explore them
+/// in, so the tiles of a chunk must be a pure function of its position (and
+/// whatever seed the generator was created with). To keep the seams between
+/// chunks coherent, generators should work in terms of the global position of
+/// each tile, rather than its position within the chunk.
+pub trait ChunkGenerator: Send + Sync {
+  /// Fills in `chunk`, which starts out as all [`Tile::Void`].
+  ///
+  /// [`Tile::Void`]: crate::map::Tile::Void
+  fn generate(&self, chunk: &mut Chunk);
+}
+
+/// How far beyond the viewport, in chunks, chunks are loaded.
+const LOAD_MARGIN: i64 = 1;
+
+/// How far beyond the viewport, in chunks, chunks are kept loaded. This is
+/// larger than [`LOAD_MARGIN`], so that pacing back and forth doesn't keep
+/// swapping the same chunks in and out.
+const UNLOAD_MARGIN: i64 = 3;
+
+/// Resource: Streams the chunks of an endless [`Floor`] in and out as the
+/// camera moves around.
+///
+/// Chunks are generated the first time they come near the viewport. Chunks
+/// that end up far away from it are written out to a scratch directory and
+/// dropped from the `Floor`, and are read back in when the camera returns.
+///
+/// Streaming never fails outright: if a chunk can't be written out, it stays
+/// loaded and no more chunks are swapped out from then on, and if a chunk
+/// can't be read back in, it is generated again from scratch. The first such
+/// error is kept around; see [`Streamer::error()`].
+pub struct Streamer {
+  generator: Box<dyn ChunkGenerator>,
+  dir: PathBuf,
+  swapped: HashSet<Point>,
+  can_swap: bool,
+  error: Option<io::Error>,
+}
+
+impl Streamer {
+  /// Creates a new `Streamer`, which swaps chunks out to a fresh directory in
+  /// the system's temporary directory.
+  pub fn new(generator: Box<dyn ChunkGenerator>) -> io::Result<Self> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = env::temp_dir().join(format!(
+      "crawl-{}-{}",
+      process::id(),
+      COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir)?;
+
+    Ok(Self {
+      generator,
+      dir,
+      swapped: HashSet::new(),
+      can_swap: true,
+      error: None,
+    })
+  }
+
+  /// Returns the first error this `Streamer` ran into while swapping chunks
+  /// in or out, if any.
+  pub fn error(&self) -> Option<&io::Error> {
+    self.error.as_ref()
+  }
+
+  /// Loads every chunk near `viewport` into `floor`, and unloads every chunk
+  /// far away from it.
+  pub fn update(&mut self, floor: &mut Floor, viewport: Rect) {
+    let chunk_dims = Rect::with_dims(WIDTH as i64, WIDTH as i64);
+    for rect in inflate(viewport, LOAD_MARGIN).disect(chunk_dims) {
+      let pos = normalize(rect.upper_left());
+      if floor.chunks.contains_key(&pos) {
+        continue;
+      }
+
+      let mut chunk = match self.swapped.contains(&pos) {
+        true => match self.swap_in(pos) {
+          Ok(chunk) => chunk,
+          Err(e) => {
+            self.error.get_or_insert(e);
+            self.generate(pos)
+          }
+        },
+        false => self.generate(pos),
+      };
+      self.swapped.remove(&pos);
+      chunk.revision = floor.bump_revision();
+      floor.chunks.insert(pos, chunk);
+    }
+
+    if !self.can_swap {
+      return;
+    }
+    let keep = inflate(viewport, UNLOAD_MARGIN);
+    let far = floor
+      .chunks
+      .values()
+      .filter(|c| c.rect().intersect(keep).is_none())
+      .map(|c| c.pos)
+      .collect::<Vec<_>>();
+    for pos in far {
+      if let Err(e) = self.swap_out(&floor.chunks[&pos]) {
+        self.error.get_or_insert(e);
+        self.can_swap = false;
+        return;
+      }
+      floor.chunks.remove(&pos);
+      self.swapped.insert(pos);
+    }
+  }
+
+  /// Generates the chunk at `pos` from scratch.
+  fn generate(&self, pos: Point) -> Chunk {
+    let mut chunk = Chunk::new(pos);
+    self.generator.generate(&mut chunk);
+    chunk
+  }
+
+  /// Reads the chunk at `pos` back in from its swap file.
+  fn swap_in(&self, pos: Point) -> io::Result<Chunk> {
+    let path = self.path(pos);
+    let chunk = Chunk::decode(&mut BufReader::new(File::open(&path)?))?;
+    // The file will be overwritten if the chunk is swapped out again, so
+    // failing to remove it doesn't matter.
+    let _ = fs::remove_file(path);
+    Ok(chunk)
+  }
+
+  /// Writes `chunk` out to its swap file.
+  fn swap_out(&self, chunk: &Chunk) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(self.path(chunk.pos))?);
+    chunk.encode(&mut file)?;
+    file.flush()
+  }
+
+  /// Returns the path the chunk at `pos` is swapped out to.
+  fn path(&self, pos: Point) -> PathBuf {
+    self.dir.join(format!("{}_{}.chunk", pos.x(), pos.y()))
+  }
+}
+
+impl Drop for Streamer {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.dir);
+  }
+}
+
+/// Grows `rect` by `chunks` chunks on every side.
+fn inflate(rect: Rect, chunks: i64) -> Rect {
+  let (start, end) = rect.corners();
+  let margin = Point::new(chunks, chunks) * WIDTH as i64;
+  Rect::new(start - margin, end + margin)
+}
+
+/// System: Streams chunks in and out around the camera.
+///
+/// This should run before anything that looks at the map around the player,
+/// such as FOV.
+#[legion::system]
+#[read_component(HasCamera)]
+#[read_component(Position)]
+pub fn stream_chunks(
+  world: &SubWorld,
+  #[resource] floor: &mut Floor,
+  #[resource] streamer: &mut Streamer,
+  #[resource] timer: &SystemTimer,
+) {
+  let _t = timer.start("map::stream::stream_chunks()");
+  let camera = <&Position>::query()
+    .filter(component::<HasCamera>())
+    .iter(world)
+    .map(|p| p.0)
+    .next();
+  if let Some(camera) = camera {
+    let (rows, cols) = curses::dims();
+    let viewport =
+      Rect::with_dims(cols as i64, rows as i64).centered_on(camera);
+    streamer.update(floor, viewport);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::map::EndlessCaves;
+  use crate::map::Tile;
+
+  /// Returns the tiles of every chunk of `floor` within `rect`.
+  fn snapshot(floor: &Floor, rect: Rect) -> Vec<(Point, Vec<Tile>)> {
+    let mut chunks = floor
+      .chunks_in(rect)
+      .map(|(_, c)| (c.pos, c.tiles.to_vec()))
+      .collect::<Vec<_>>();
+    chunks.sort_by_key(|(p, _)| (p.y(), p.x()));
+    chunks
+  }
+
+  #[test]
+  fn swapped_chunks_come_back_unchanged() {
+    let caves = EndlessCaves::new(3, 0.45, 4);
+    let mut streamer = Streamer::new(Box::new(caves)).unwrap();
+    let mut floor = Floor::new();
+
+    let home = Rect::with_dims(64, 64).centered_on(Point::zero());
+    streamer.update(&mut floor, home);
+    let dug = Point::new(-5, 7);
+    *floor.chunk_mut(dug).tile_mut(dug) = Tile::Rubble;
+    let before = snapshot(&floor, home);
+    assert!(!before.is_empty());
+
+    let away = Rect::with_dims(64, 64).centered_on(Point::new(1000, -1000));
+    streamer.update(&mut floor, away);
+    assert!(floor.chunk(dug).is_none());
+
+    streamer.update(&mut floor, home);
+    assert!(streamer.error().is_none());
+    assert_eq!(snapshot(&floor, home), before);
+    assert_eq!(floor.tile(dug), Tile::Rubble);
+  }
+}
@@ -0,0 +1,102 @@
+//! Decorating rooms with rough and hazardous terrain.
+
+use rand::Rng;
+
+use crate::geo::Point;
+use crate::geo::Rect;
+use crate::map::Floor;
+use crate::map::RoomKind;
+use crate::map::Tile;
+
+/// The chance that a room gets a terrain feature.
+const FEATURE_CHANCE: f64 = 0.3;
+
+/// The chance that each tile of a rubble-strewn room is rubble.
+const RUBBLE_DENSITY: f64 = 0.2;
+
+impl Floor {
+  /// Scatters pools of water, lava and rubble around the ordinary rooms and
+  /// caverns of this floor.
+  ///
+  /// Features never cut a room off: deep water and lava are kept off of the
+  /// row and column through a room's center and the ring of tiles just inside
+  /// its walls, so every doorway stays reachable from the center without
+  /// swimming or getting burned. The first room is left alone, since that is
+  /// where the player arrives.
+  pub fn add_terrain(&mut self, rng: &mut impl Rng) {
+    for i in 1..self.rooms.len() {
+      let room = &self.rooms[i];
+      if !matches!(room.kind, RoomKind::Plain | RoomKind::Cavern)
+        || !rng.gen_bool(FEATURE_CHANCE)
+      {
+        continue;
+      }
+
+      // The interior, less the ring just inside the walls.
+      let (start, end) = room.rect.corners();
+      let inner = Rect::new(start + Point::new(2, 2), end - Point::new(2, 2));
+      if inner.width() < 3 || inner.height() < 3 {
+        continue;
+      }
+      let center = room.center();
+
+      let (core, edge) = match rng.gen_range(0..4) {
+        0 => (Tile::DeepWater, Tile::ShallowWater),
+        1 => (Tile::ShallowWater, Tile::ShallowWater),
+        2 => (Tile::Lava, Tile::Rubble),
+        _ => {
+          for p in inner.points() {
+            if rng.gen_bool(RUBBLE_DENSITY) {
+              self.replace_ground(p, Tile::Rubble);
+            }
+          }
+          continue;
+        }
+      };
+      self.add_pool(rng, inner, center, core, edge);
+    }
+  }
+
+  /// Adds a roughly elliptical pool within `inner`, with a core of `core`
+  /// surrounded by `edge`.
+  ///
+  /// The core is kept off of the row and column through `center`.
+  fn add_pool(
+    &mut self,
+    rng: &mut impl Rng,
+    inner: Rect,
+    center: Point,
+    core: Tile,
+    edge: Tile,
+  ) {
+    let (start, end) = inner.corners();
+    let pool = Point::new(
+      rng.gen_range(start.x()..end.x()),
+      rng.gen_range(start.y()..end.y()),
+    );
+    let radii = (
+      rng.gen_range(1.5..(inner.width() as f64 / 2.0).max(2.0)),
+      rng.gen_range(1.5..(inner.height() as f64 / 2.0).max(2.0)),
+    );
+
+    for p in inner.points() {
+      let d = p - pool;
+      let r =
+        (d.x() as f64 / radii.0).powi(2) + (d.y() as f64 / radii.1).powi(2);
+      let on_axis = p.x() == center.x() || p.y() == center.y();
+      if r <= 0.4 && !on_axis {
+        self.replace_ground(p, core);
+      } else if r <= 1.0 {
+        self.replace_ground(p, edge);
+      }
+    }
+  }
+
+  /// Replaces the tile at `p` with `tile`, if it is currently ground.
+  fn replace_ground(&mut self, p: Point, tile: Tile) {
+    let slot = self.chunk_mut(p).tile_mut(p);
+    if *slot == Tile::Ground {
+      *slot = tile;
+    }
+  }
+}
@@ -0,0 +1,504 @@
+//! Tile kinds and their properties.
+//!
+//! A [`Tile`] only identifies what kind of terrain occupies a point on a
+//! [`Floor`](crate::map::Floor); everything else about it, such as how it is
+//! drawn and whether it can be walked on, is described by a [`TileDef`] in a
+//! [`Tiles`] registry, which is loaded from a data file.
+//!
+//! See `data/tiles.txt` for the format.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use crate::gfx::texel::colors;
+use crate::gfx::texel::Color;
+use crate::gfx::texel::Rgb;
+use crate::gfx::texel::Texel;
+use crate::save;
+use crate::save::Decode;
+use crate::save::Encode;
+
+macro_rules! tiles {
+  ($($variant:ident => $name:literal,)*) => {
+    /// A kind of tile.
+    ///
+    /// Tiles are ordered by how "open" they are; when a generator places a tile
+    /// on top of another, the greater one wins, so that e.g. corridors can
+    /// carve through walls but not the other way around.
+    #[allow(missing_docs)]
+    #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+    pub enum Tile {
+      $($variant,)*
+    }
+
+    impl Tile {
+      /// Every tile kind, in order.
+      pub const ALL: &'static [Tile] = &[$(Tile::$variant,)*];
+
+      /// Returns the name this tile is referred to by in data files.
+      pub fn name(self) -> &'static str {
+        match self {
+          $(Tile::$variant => $name,)*
+        }
+      }
+
+      /// Looks up a tile by the name it is referred to by in data files.
+      pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+          $($name => Some(Tile::$variant),)*
+          _ => None,
+        }
+      }
+    }
+  };
+}
+
+tiles! {
+  Void => "void",
+  Wall => "wall",
+  Ground => "ground",
+  DoorClosed => "door_closed",
+  DoorOpen => "door_open",
+  StairsDown => "stairs_down",
+  StairsUp => "stairs_up",
+  Rubble => "rubble",
+  ShallowWater => "shallow_water",
+  DeepWater => "deep_water",
+  Lava => "lava",
+  SecretDoor => "secret_door",
+  TrapHidden => "trap_hidden",
+  Trap => "trap",
+  CacheHidden => "cache_hidden",
+  Cache => "cache",
+}
+
+// Tiles are saved by name, so that adding new ones doesn't invalidate saves.
+impl Encode for Tile {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.name().encode(w)
+  }
+}
+
+impl Decode for Tile {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let name = String::decode(r)?;
+    Tile::from_name(&name)
+      .ok_or_else(|| save::invalid(format!("unknown tile `{}`", name)))
+  }
+}
+
+bitflags::bitflags! {
+  /// Properties of a [`TileDef`].
+  pub struct TileFlags: u32 {
+    /// Actors may stand on this tile.
+    const WALKABLE = 1 << 0;
+    /// This tile blocks line of sight.
+    const OPAQUE = 1 << 1;
+    /// Actors that can swim may stand on this tile, even if it isn't
+    /// walkable.
+    const SWIMMABLE = 1 << 2;
+    /// This tile springs when an actor steps onto it, dealing its damage
+    /// once rather than every turn.
+    const TRAP = 1 << 3;
+  }
+}
+
+impl TileFlags {
+  /// Looks up a flag by the name it is given in data files.
+  fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "walkable" => Some(Self::WALKABLE),
+      "opaque" => Some(Self::OPAQUE),
+      "swimmable" => Some(Self::SWIMMABLE),
+      "trap" => Some(Self::TRAP),
+      _ => None,
+    }
+  }
+}
+
+/// The definition of a [`Tile`].
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct TileDef {
+  /// How this tile is drawn.
+  pub texel: Texel,
+  /// This tile's properties.
+  pub flags: TileFlags,
+  /// The relative cost of walking across this tile, which is at least 1.
+  ///
+  /// Besides steering pathfinding, this slows actors down: stepping onto a
+  /// tile takes as many turns as its cost, rounded up.
+  pub cost: f64,
+  /// How much damage an actor takes for each turn it spends on this tile, or,
+  /// for a trap, when it springs.
+  pub damage: u32,
+  /// How much gold an actor picks up from this tile, leaving bare ground
+  /// behind.
+  pub gold: u32,
+  /// The tile this one turns into when an actor bumps into it.
+  pub opens_into: Option<Tile>,
+  /// The tile this one turns into when an actor digs through it.
+  pub digs_into: Option<Tile>,
+  /// The tile this one turns into once it is discovered, if it is hidden.
+  pub reveals_into: Option<Tile>,
+}
+
+impl TileDef {
+  /// Returns whether actors may stand on this tile.
+  pub fn is_walkable(&self) -> bool {
+    self.flags.contains(TileFlags::WALKABLE)
+  }
+
+  /// Returns whether this tile blocks line of sight.
+  pub fn is_opaque(&self) -> bool {
+    self.flags.contains(TileFlags::OPAQUE)
+  }
+
+  /// Returns whether this tile is a trap.
+  pub fn is_trap(&self) -> bool {
+    self.flags.contains(TileFlags::TRAP)
+  }
+
+  /// Returns whether this tile is hidden, passing for some other tile until
+  /// it is discovered.
+  pub fn is_hidden(&self) -> bool {
+    self.reveals_into.is_some()
+  }
+
+  /// Returns whether actors can make their way across this tile, possibly by
+  /// opening it first.
+  pub fn is_passable(&self) -> bool {
+    self.is_walkable() || self.opens_into.is_some()
+  }
+
+  /// Returns whether an actor that gets around by `mobility` may stand on this
+  /// tile.
+  pub fn can_enter(&self, mobility: Mobility) -> bool {
+    self.is_walkable()
+      || (mobility.swims && self.flags.contains(TileFlags::SWIMMABLE))
+  }
+
+  /// Returns the cost of stepping onto this tile when pathfinding, or `None`
+  /// if an actor that gets around by `mobility` can't make its way across it
+  /// at all.
+  ///
+  /// Hazardous tiles cost extra, so that actors steer around them, as do tiles
+  /// that have to be dug through, so that actors only tunnel when it saves
+  /// them a long walk.
+  pub fn step_cost(&self, mobility: Mobility) -> Option<f64> {
+    if self.can_enter(mobility) || self.opens_into.is_some() {
+      Some(self.cost + self.damage as f64)
+    } else if mobility.digs && self.digs_into.is_some() {
+      Some(self.cost + DIG_COST)
+    } else {
+      None
+    }
+  }
+
+  /// Returns how many turns an actor must wait after stepping onto this tile
+  /// before it can move again.
+  pub fn delay(&self) -> u32 {
+    (self.cost.ceil() as u32).saturating_sub(1)
+  }
+}
+
+/// The ways an actor can get around, beyond walking.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Mobility {
+  /// Whether the actor can swim through swimmable tiles.
+  pub swims: bool,
+  /// Whether the actor can dig through diggable tiles.
+  pub digs: bool,
+}
+
+/// The extra cost of digging through a tile, for [`TileDef::step_cost()`].
+const DIG_COST: f64 = 8.0;
+
+/// Resource: A registry of [`TileDef`]s for every [`Tile`].
+pub struct Tiles {
+  defs: Vec<TileDef>,
+}
+
+impl Tiles {
+  /// Returns the tile definitions built into the game.
+  pub fn builtin() -> Self {
+    Self::parse(include_str!("../../data/tiles.txt"))
+      .expect("built-in tile definitions should be valid")
+  }
+
+  /// Loads tile definitions from the file at `path`.
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, ParseError> {
+    let src = fs::read_to_string(path).map_err(|e| ParseError {
+      line: 0,
+      message: e.to_string(),
+    })?;
+    Self::parse(&src)
+  }
+
+  /// Parses tile definitions from `src`.
+  ///
+  /// Every [`Tile`] must be defined exactly once.
+  pub fn parse(src: &str) -> Result<Self, ParseError> {
+    let mut defs = vec![None; Tile::ALL.len()];
+    let mut disguises = Vec::new();
+    let mut current = None;
+    for (i, line) in src.lines().enumerate() {
+      let err = |message: String| ParseError {
+        line: i + 1,
+        message,
+      };
+
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      if let Some(name) =
+        line.strip_prefix('[').and_then(|l| l.strip_suffix(']'))
+      {
+        let tile = Tile::from_name(name)
+          .ok_or_else(|| err(format!("unknown tile `{}`", name)))?;
+        if defs[tile as usize].is_some() {
+          return Err(err(format!("tile `{}` defined twice", name)));
+        }
+
+        defs[tile as usize] = Some(TileDef {
+          texel: Texel::new(' '),
+          flags: TileFlags::empty(),
+          cost: 1.0,
+          damage: 0,
+          gold: 0,
+          opens_into: None,
+          digs_into: None,
+          reveals_into: None,
+        });
+        current = Some(tile);
+        continue;
+      }
+
+      let tile = current.ok_or_else(|| err("expected a `[tile]`".into()))?;
+      let def = defs[tile as usize].as_mut().unwrap();
+      let (key, value) = match line.find('=') {
+        Some(idx) => (line[..idx].trim(), line[idx + 1..].trim()),
+        None => return Err(err("expected `key = value`".into())),
+      };
+
+      match key {
+        "glyph" => {
+          let glyph = unquote(value);
+          let mut chars = glyph.chars();
+          match (chars.next(), chars.next()) {
+            (Some(c), None) => def.texel = def.texel.with_glyph(c),
+            _ => return Err(err(format!("invalid glyph `{}`", value))),
+          }
+        }
+        "fg" => def.texel = def.texel.with_fg(parse_color(value).map_err(err)?),
+        "bg" => def.texel = def.texel.with_bg(parse_color(value).map_err(err)?),
+        "flags" => {
+          for name in value.split_whitespace() {
+            def.flags |= TileFlags::from_name(name)
+              .ok_or_else(|| err(format!("unknown flag `{}`", name)))?;
+          }
+        }
+        "cost" => {
+          def.cost = value
+            .parse()
+            .ok()
+            .filter(|&cost: &f64| cost >= 1.0)
+            .ok_or_else(|| err(format!("invalid cost `{}`", value)))?;
+        }
+        "damage" => {
+          def.damage = value
+            .parse()
+            .map_err(|_| err(format!("invalid damage `{}`", value)))?;
+        }
+        "opens_into" => {
+          def.opens_into = Some(
+            Tile::from_name(value)
+              .ok_or_else(|| err(format!("unknown tile `{}`", value)))?,
+          );
+        }
+        "gold" => {
+          def.gold = value
+            .parse()
+            .map_err(|_| err(format!("invalid gold `{}`", value)))?;
+        }
+        "digs_into" => {
+          def.digs_into = Some(
+            Tile::from_name(value)
+              .ok_or_else(|| err(format!("unknown tile `{}`", value)))?,
+          );
+        }
+        "reveals_into" => {
+          def.reveals_into = Some(
+            Tile::from_name(value)
+              .ok_or_else(|| err(format!("unknown tile `{}`", value)))?,
+          );
+        }
+        "looks_like" => {
+          let other = Tile::from_name(value)
+            .ok_or_else(|| err(format!("unknown tile `{}`", value)))?;
+          disguises.push((tile, other, i + 1));
+        }
+        _ => return Err(err(format!("unknown key `{}`", key))),
+      }
+    }
+
+    let mut defs = defs
+      .into_iter()
+      .zip(Tile::ALL)
+      .map(|(def, tile)| {
+        def.ok_or_else(|| ParseError {
+          line: 0,
+          message: format!("missing definition for tile `{}`", tile.name()),
+        })
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+
+    // Disguises can only be filled in once every tile is defined, since they
+    // may refer to tiles further down.
+    for &(tile, other, line) in &disguises {
+      if disguises.iter().any(|&(t, _, _)| t == other) {
+        return Err(ParseError {
+          line,
+          message: format!("tile `{}` is itself disguised", other.name()),
+        });
+      }
+      defs[tile as usize].texel = defs[other as usize].texel;
+    }
+    Ok(Self { defs })
+  }
+
+  /// Returns the definition for `tile`.
+  pub fn get(&self, tile: Tile) -> &TileDef {
+    &self.defs[tile as usize]
+  }
+}
+
+/// Strips a pair of single or double quotes from `s`, if present.
+pub(super) fn unquote(s: &str) -> &str {
+  for q in &['\'', '"'] {
+    if let Some(s) = s.strip_prefix(*q).and_then(|s| s.strip_suffix(*q)) {
+      return s;
+    }
+  }
+  s
+}
+
+/// Parses either a CSS color name or a `#rrggbb` triple.
+fn parse_color(s: &str) -> Result<Color, String> {
+  if let Some(hex) = s.strip_prefix('#') {
+    let rgb = u32::from_str_radix(hex, 16)
+      .ok()
+      .filter(|_| hex.len() == 6)
+      .ok_or_else(|| format!("invalid color `{}`", s))?;
+    let [_, r, g, b] = rgb.to_be_bytes();
+    return Ok(Rgb::new(r, g, b).into());
+  }
+
+  colors::from_str(s)
+    .map(Color::from)
+    .ok_or_else(|| format!("unknown color `{}`", s))
+}
+
+/// An error from parsing tile definitions.
+#[derive(Clone, Debug)]
+pub struct ParseError {
+  /// The line the error occured on, or zero if it is not specific to a line.
+  pub line: usize,
+  /// A description of the error.
+  pub message: String,
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.line {
+      0 => write!(f, "{}", self.message),
+      n => write!(f, "line {}: {}", n, self.message),
+    }
+  }
+}
+
+impl Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const BUILTIN: &str = include_str!("../../data/tiles.txt");
+
+  /// Parses the built-in tile definitions with `from` replaced by `to`, and
+  /// returns the error, which should be on the line reading `bad`.
+  fn parse_err(from: &str, to: &str, bad: &str) -> ParseError {
+    assert_eq!(BUILTIN.matches(from).count(), 1, "{}", from);
+    let src = BUILTIN.replace(from, to);
+    let e = match Tiles::parse(&src) {
+      Ok(_) => panic!("parsed successfully with `{}`", to),
+      Err(e) => e,
+    };
+    let lines = src.lines().collect::<Vec<_>>();
+    assert_eq!(lines.get(e.line.wrapping_sub(1)), Some(&bad), "{}", e);
+    e
+  }
+
+  #[test]
+  fn builtin_tiles_parse() {
+    let tiles = Tiles::parse(BUILTIN).unwrap();
+    assert!(tiles.get(Tile::Ground).is_walkable());
+    assert!(tiles.get(Tile::Wall).is_opaque());
+    assert_eq!(tiles.get(Tile::Wall).digs_into, Some(Tile::Ground));
+    assert_eq!(tiles.get(Tile::DoorClosed).opens_into, Some(Tile::DoorOpen));
+    assert!(tiles.get(Tile::Trap).is_trap());
+
+    // Hidden tiles look just like what they pass for.
+    assert!(tiles.get(Tile::TrapHidden).is_hidden());
+    assert_eq!(
+      tiles.get(Tile::TrapHidden).texel,
+      tiles.get(Tile::Ground).texel
+    );
+    assert_eq!(
+      tiles.get(Tile::SecretDoor).texel,
+      tiles.get(Tile::Wall).texel
+    );
+  }
+
+  #[test]
+  fn unknown_flag() {
+    let e = parse_err(
+      "[ground]\nglyph = .\nflags = walkable\n",
+      "[ground]\nglyph = .\nflags = walkable flying\n",
+      "flags = walkable flying",
+    );
+    assert_eq!(e.message, "unknown flag `flying`");
+  }
+
+  #[test]
+  fn unknown_targets() {
+    let e = parse_err(
+      "opens_into = door_open",
+      "opens_into = door_ajar",
+      "opens_into = door_ajar",
+    );
+    assert_eq!(e.message, "unknown tile `door_ajar`");
+
+    let e = parse_err(
+      "[wall]\nglyph = +\nflags = opaque\ndigs_into = ground\n",
+      "[wall]\nglyph = +\nflags = opaque\ndigs_into = dirt\n",
+      "digs_into = dirt",
+    );
+    assert_eq!(e.message, "unknown tile `dirt`");
+  }
+
+  #[test]
+  fn duplicate_tiles() {
+    let e = parse_err("[cache]", "[wall]\n\n[cache]", "[wall]");
+    assert_eq!(e.message, "tile `wall` defined twice");
+
+    let e = parse_err("[cache]", "[cache]\n\n[cache]", "[cache]");
+    assert_eq!(e.message, "tile `cache` defined twice");
+  }
+}
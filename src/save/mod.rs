@@ -0,0 +1,360 @@
+//! Binary save files.
+//!
+//! Types that can be persisted implement [`Encode`] and [`Decode`], which
+//! write and read them in a compact little-endian format. The format has no
+//! self-description beyond what each type chooses to write, so anything with
+//! a non-trivial layout should write a version number of its own.
+//!
+//! Entities and resources are saved through a [`Registry`], which keeps track
+//! of which types need saving and how to refer to entities across a save.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use crate::geo::Dir;
+use crate::geo::Point;
+use crate::geo::Rect;
+
+mod world;
+
+pub use world::Reader;
+pub use world::Registry;
+pub use world::Writer;
+
+/// A type that can be written to a save file.
+pub trait Encode {
+  /// Writes `self` to `w`.
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()>;
+}
+
+/// A type that can be read from a save file.
+pub trait Decode: Sized {
+  /// Reads a value from `r`.
+  fn decode(r: &mut dyn Read) -> io::Result<Self>;
+}
+
+/// Returns an error describing a malformed save file.
+pub fn invalid(msg: impl Into<String>) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Writes a "magic" header and version number to `w`.
+///
+/// See [`expect_header()`].
+pub fn write_header(
+  w: &mut dyn Write,
+  magic: &[u8; 4],
+  version: u32,
+) -> io::Result<()> {
+  w.write_all(magic)?;
+  version.encode(w)
+}
+
+/// Reads a header written by [`write_header()`], checking that it has the
+/// expected magic bytes and a version no newer than `max_version`.
+///
+/// Returns the version that was read.
+pub fn expect_header(
+  r: &mut dyn Read,
+  magic: &[u8; 4],
+  max_version: u32,
+) -> io::Result<u32> {
+  let mut buf = [0; 4];
+  r.read_exact(&mut buf)?;
+  if &buf != magic {
+    return Err(invalid(format!(
+      "expected {:?} header, got {:?}",
+      String::from_utf8_lossy(magic),
+      String::from_utf8_lossy(&buf)
+    )));
+  }
+
+  let version = u32::decode(r)?;
+  if version == 0 || version > max_version {
+    return Err(invalid(format!("unsupported version {}", version)));
+  }
+  Ok(version)
+}
+
+macro_rules! int_impls {
+  ($($ty:ty,)*) => {$(
+    impl Encode for $ty {
+      fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.to_le_bytes())
+      }
+    }
+
+    impl Decode for $ty {
+      fn decode(r: &mut dyn Read) -> io::Result<Self> {
+        let mut buf = [0; std::mem::size_of::<$ty>()];
+        r.read_exact(&mut buf)?;
+        Ok(<$ty>::from_le_bytes(buf))
+      }
+    }
+  )*}
+}
+
+int_impls! {
+  u8, u16, u32, u64, i32, i64, f64,
+}
+
+impl Encode for bool {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    (*self as u8).encode(w)
+  }
+}
+
+impl Decode for bool {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    match u8::decode(r)? {
+      0 => Ok(false),
+      1 => Ok(true),
+      n => Err(invalid(format!("invalid bool {}", n))),
+    }
+  }
+}
+
+impl Encode for usize {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    (*self as u64).encode(w)
+  }
+}
+
+impl Decode for usize {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let n = u64::decode(r)?;
+    if n > usize::MAX as u64 {
+      return Err(invalid(format!("length {} is too large", n)));
+    }
+    Ok(n as usize)
+  }
+}
+
+impl Encode for str {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.len().encode(w)?;
+    w.write_all(self.as_bytes())
+  }
+}
+
+impl Encode for String {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.as_str().encode(w)
+  }
+}
+
+impl Decode for String {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let len = usize::decode(r)?;
+    let mut buf = Vec::new();
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(buf).map_err(|e| invalid(e.to_string()))
+  }
+}
+
+impl<T: Encode> Encode for Option<T> {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.is_some().encode(w)?;
+    match self {
+      Some(x) => x.encode(w),
+      None => Ok(()),
+    }
+  }
+}
+
+impl<T: Decode> Decode for Option<T> {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    match bool::decode(r)? {
+      true => Ok(Some(T::decode(r)?)),
+      false => Ok(None),
+    }
+  }
+}
+
+impl<T: Encode> Encode for [T] {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.len().encode(w)?;
+    for x in self {
+      x.encode(w)?;
+    }
+    Ok(())
+  }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.as_slice().encode(w)
+  }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let len = usize::decode(r)?;
+    // Don't trust `len` for the allocation, since the file may be corrupt.
+    let mut vec = Vec::with_capacity(len.min(1024));
+    for _ in 0..len {
+      vec.push(T::decode(r)?);
+    }
+    Ok(vec)
+  }
+}
+
+impl<T: Encode> Encode for HashSet<T> {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.len().encode(w)?;
+    for x in self {
+      x.encode(w)?;
+    }
+    Ok(())
+  }
+}
+
+impl<T: Decode + Eq + Hash> Decode for HashSet<T> {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    Ok(Vec::decode(r)?.into_iter().collect())
+  }
+}
+
+impl<K: Encode, V: Encode> Encode for HashMap<K, V> {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.len().encode(w)?;
+    for (k, v) in self {
+      k.encode(w)?;
+      v.encode(w)?;
+    }
+    Ok(())
+  }
+}
+
+impl<K: Decode + Eq + Hash, V: Decode> Decode for HashMap<K, V> {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let len = usize::decode(r)?;
+    let mut map = HashMap::with_capacity(len.min(1024));
+    for _ in 0..len {
+      let k = K::decode(r)?;
+      let v = V::decode(r)?;
+      map.insert(k, v);
+    }
+    Ok(map)
+  }
+}
+
+impl<T: Encode + Copy> Encode for Point<T> {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.x().encode(w)?;
+    self.y().encode(w)
+  }
+}
+
+impl<T: Decode> Decode for Point<T> {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let x = T::decode(r)?;
+    let y = T::decode(r)?;
+    Ok(Point::new(x, y))
+  }
+}
+
+impl Encode for Rect {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    self.upper_left().encode(w)?;
+    self.lower_right().encode(w)
+  }
+}
+
+impl Decode for Rect {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let start = Point::decode(r)?;
+    let end = Point::decode(r)?;
+    Ok(Rect::new(start, end))
+  }
+}
+
+impl Encode for Dir {
+  fn encode(&self, w: &mut dyn Write) -> io::Result<()> {
+    let idx = Dir::all().iter().position(|d| d == self).unwrap();
+    (idx as u8).encode(w)
+  }
+}
+
+impl Decode for Dir {
+  fn decode(r: &mut dyn Read) -> io::Result<Self> {
+    let idx = u8::decode(r)?;
+    Dir::all()
+      .get(idx as usize)
+      .copied()
+      .ok_or_else(|| invalid(format!("invalid direction {}", idx)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::fmt::Debug;
+
+  /// Checks that `val` survives being encoded and decoded, and that nothing is
+  /// left over afterwards.
+  fn round_trip<T: Encode + Decode + PartialEq + Debug>(val: T) {
+    let mut buf = Vec::new();
+    val.encode(&mut buf).unwrap();
+
+    let mut r = buf.as_slice();
+    let decoded = T::decode(&mut r).unwrap();
+    assert_eq!(decoded, val);
+    assert!(r.is_empty(), "{} bytes left over", r.len());
+  }
+
+  #[test]
+  fn point() {
+    round_trip(Point::new(0i64, 0));
+    round_trip(Point::new(-32i64, -64));
+    round_trip(Point::new(i64::MIN, i64::MAX));
+    round_trip(Point::new(-7i32, 12));
+  }
+
+  #[test]
+  fn rect() {
+    round_trip(Rect::new(Point::new(-32, -64), Point::new(-1, -33)));
+    round_trip(Rect::new(Point::new(-5, 5), Point::new(5, -5)));
+    round_trip(Rect::with_dims(0, 0));
+  }
+
+  #[test]
+  fn option() {
+    round_trip(None::<Point>);
+    round_trip(Some(Point::new(-3, 4)));
+    round_trip(Some(Some(false)));
+  }
+
+  #[test]
+  fn vec() {
+    round_trip(Vec::<u8>::new());
+    round_trip(vec![Point::new(-1, -1), Point::new(2, 3)]);
+    round_trip(vec![String::from("wall"), String::new()]);
+  }
+
+  #[test]
+  fn hash_map() {
+    round_trip(HashMap::<Point, String>::new());
+    round_trip(
+      (-3..3)
+        .map(|i| (Point::new(i, -i), vec![i as u32; (i + 3) as usize]))
+        .collect::<HashMap<_, _>>(),
+    );
+  }
+
+  #[test]
+  fn truncated_input_is_an_error() {
+    let mut buf = Vec::new();
+    vec![Point::new(-32i64, -64)].encode(&mut buf).unwrap();
+    buf.pop();
+    assert!(Vec::<Point>::decode(&mut buf.as_slice()).is_err());
+  }
+}
@@ -0,0 +1,307 @@
+//! Saving and loading ECS state.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use legion::storage::Component;
+use legion::systems::Resource;
+use legion::Entity;
+use legion::IntoQuery as _;
+use legion::Resources;
+use legion::World;
+
+use crate::save::invalid;
+use crate::save::Decode;
+use crate::save::Encode;
+
+/// A save file being written, which knows how to refer to entities.
+///
+/// Entities are written as indices into the list of every entity in the save,
+/// since their actual IDs will be different once they are loaded.
+pub struct Writer<'a> {
+  inner: &'a mut dyn Write,
+  entities: &'a HashMap<Entity, u64>,
+}
+
+impl Writer<'_> {
+  /// Writes a reference to `entity`.
+  ///
+  /// References to entities that are not being saved are written as `None`.
+  pub fn entity(&mut self, entity: Option<Entity>) -> io::Result<()> {
+    let idx = entity.and_then(|e| self.entities.get(&e));
+    match idx {
+      Some(&idx) => (idx + 1).encode(self),
+      None => 0u64.encode(self),
+    }
+  }
+}
+
+impl Write for Writer<'_> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.inner.write(buf)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// A save file being read; see [`Writer`].
+pub struct Reader<'a> {
+  inner: &'a mut dyn Read,
+  entities: &'a [Entity],
+}
+
+impl Reader<'_> {
+  /// Reads a reference to an entity written with [`Writer::entity()`].
+  pub fn entity(&mut self) -> io::Result<Option<Entity>> {
+    match u64::decode(self)? {
+      0 => Ok(None),
+      n => self
+        .entities
+        .get(n as usize - 1)
+        .copied()
+        .map(Some)
+        .ok_or_else(|| invalid(format!("entity {} is out of range", n - 1))),
+    }
+  }
+}
+
+impl Read for Reader<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.inner.read(buf)
+  }
+}
+
+type SaveComponents =
+  Box<dyn Fn(&World, &mut Writer) -> io::Result<()> + Send + Sync>;
+type LoadComponents =
+  Box<dyn Fn(&mut World, &mut Reader) -> io::Result<()> + Send + Sync>;
+type SaveResource =
+  Box<dyn Fn(&Resources, &mut dyn Write) -> io::Result<()> + Send + Sync>;
+type LoadResource =
+  Box<dyn Fn(&mut Resources, &mut dyn Read) -> io::Result<()> + Send + Sync>;
+
+/// A registry of every component and resource type that goes in a save file.
+///
+/// Each type is saved under a name, which must be unique among components
+/// and among resources. Anything that isn't registered is simply not saved.
+pub struct Registry {
+  components: Vec<(&'static str, SaveComponents, LoadComponents)>,
+  resources: Vec<(&'static str, SaveResource, LoadResource)>,
+}
+
+impl Registry {
+  /// Creates a new, empty `Registry`.
+  pub fn new() -> Self {
+    Self {
+      components: Vec::new(),
+      resources: Vec::new(),
+    }
+  }
+
+  /// Registers a component type that implements [`Encode`] and [`Decode`].
+  pub fn component<T>(&mut self, name: &'static str) -> &mut Self
+  where
+    T: Component + Encode + Decode,
+  {
+    self.component_with(name, |x: &T, w| x.encode(w), |r| T::decode(r))
+  }
+
+  /// Registers a component type that is saved and loaded by the given
+  /// functions, for components that refer to other entities.
+  pub fn component_with<T: Component>(
+    &mut self,
+    name: &'static str,
+    save: impl Fn(&T, &mut Writer) -> io::Result<()> + Send + Sync + 'static,
+    load: impl Fn(&mut Reader) -> io::Result<T> + Send + Sync + 'static,
+  ) -> &mut Self {
+    assert!(
+      self.components.iter().all(|(n, _, _)| *n != name),
+      "component `{}` registered twice",
+      name
+    );
+
+    let save = move |world: &World, w: &mut Writer| {
+      let entities = <(Entity, &T)>::query().iter(world).collect::<Vec<_>>();
+      entities.len().encode(w)?;
+      for (&e, x) in entities {
+        w.entity(Some(e))?;
+        save(x, w)?;
+      }
+      Ok(())
+    };
+    let load = move |world: &mut World, r: &mut Reader| {
+      let len = usize::decode(r)?;
+      for _ in 0..len {
+        let e = r.entity()?;
+        let x = load(r)?;
+        match e.and_then(|e| world.entry(e)) {
+          Some(mut entry) => entry.add_component(x),
+          None => return Err(invalid(format!("misplaced `{}`", name))),
+        }
+      }
+      Ok(())
+    };
+
+    self.components.push((name, Box::new(save), Box::new(load)));
+    self
+  }
+
+  /// Registers a resource type that implements [`Encode`] and [`Decode`].
+  ///
+  /// When loading, the resource is replaced wholesale.
+  pub fn resource<T>(&mut self, name: &'static str) -> &mut Self
+  where
+    T: Resource + Encode + Decode,
+  {
+    self.push_resource(
+      name,
+      Box::new(move |resources, w| {
+        resources
+          .get::<T>()
+          .ok_or_else(|| invalid(format!("missing resource `{}`", name)))?
+          .encode(w)
+      }),
+      Box::new(|resources, r| {
+        resources.insert(T::decode(r)?);
+        Ok(())
+      }),
+    )
+  }
+
+  /// Registers a resource type that is saved and loaded by the given
+  /// functions.
+  ///
+  /// Only part of the resource need be saved: when loading, `load` is called
+  /// on the existing resource, which must already be present.
+  pub fn resource_with<T: Resource>(
+    &mut self,
+    name: &'static str,
+    save: impl Fn(&T, &mut dyn Write) -> io::Result<()> + Send + Sync + 'static,
+    load: impl Fn(&mut T, &mut dyn Read) -> io::Result<()> + Send + Sync + 'static,
+  ) -> &mut Self {
+    self.push_resource(
+      name,
+      Box::new(move |resources, w| {
+        let x = resources
+          .get::<T>()
+          .ok_or_else(|| invalid(format!("missing resource `{}`", name)))?;
+        save(&x, w)
+      }),
+      Box::new(move |resources, r| {
+        let mut x = resources
+          .get_mut::<T>()
+          .ok_or_else(|| invalid(format!("missing resource `{}`", name)))?;
+        load(&mut x, r)
+      }),
+    )
+  }
+
+  fn push_resource(
+    &mut self,
+    name: &'static str,
+    save: SaveResource,
+    load: LoadResource,
+  ) -> &mut Self {
+    assert!(
+      self.resources.iter().all(|(n, _, _)| *n != name),
+      "resource `{}` registered twice",
+      name
+    );
+    self.resources.push((name, save, load));
+    self
+  }
+
+  /// Saves every registered resource, followed by the registered components
+  /// of every entity in `worlds`.
+  ///
+  /// Components may refer to entities in any of the worlds.
+  pub fn save(
+    &self,
+    worlds: &[&World],
+    resources: &Resources,
+    w: &mut dyn Write,
+  ) -> io::Result<()> {
+    self.resources.len().encode(w)?;
+    for (name, save, _) in &self.resources {
+      name.encode(w)?;
+      save(resources, w)?;
+    }
+
+    let mut entities = HashMap::new();
+    worlds.len().encode(w)?;
+    for world in worlds {
+      let len = entities.len();
+      for &e in <Entity>::query().iter(*world) {
+        entities.insert(e, entities.len() as u64);
+      }
+      (entities.len() - len).encode(w)?;
+    }
+
+    let mut w = Writer {
+      inner: w,
+      entities: &entities,
+    };
+    for world in worlds {
+      self.components.len().encode(&mut w)?;
+      for (name, save, _) in &self.components {
+        name.encode(&mut w)?;
+        save(world, &mut w)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Loads resources and entities saved with [`Registry::save()`].
+  ///
+  /// Loaded resources are inserted into `resources`, and the loaded worlds are
+  /// returned in the order they were saved in.
+  pub fn load(
+    &self,
+    r: &mut dyn Read,
+    resources: &mut Resources,
+  ) -> io::Result<Vec<World>> {
+    let len = usize::decode(r)?;
+    for _ in 0..len {
+      let name = String::decode(r)?;
+      let (_, _, load) = self
+        .resources
+        .iter()
+        .find(|(n, _, _)| *n == name)
+        .ok_or_else(|| invalid(format!("unknown resource `{}`", name)))?;
+      load(resources, r)?;
+    }
+
+    // Every entity needs to exist before we can load any components, since
+    // they may refer to each other.
+    let mut worlds = Vec::new();
+    let mut entities = Vec::new();
+    for _ in 0..usize::decode(r)? {
+      let mut world = World::default();
+      for _ in 0..usize::decode(r)? {
+        entities.push(world.push(()));
+      }
+      worlds.push(world);
+    }
+
+    let mut r = Reader {
+      inner: r,
+      entities: &entities,
+    };
+    for world in &mut worlds {
+      for _ in 0..usize::decode(&mut r)? {
+        let name = String::decode(&mut r)?;
+        let (_, _, load) = self
+          .components
+          .iter()
+          .find(|(n, _, _)| *n == name)
+          .ok_or_else(|| invalid(format!("unknown component `{}`", name)))?;
+        load(world, &mut r)?;
+      }
+    }
+    Ok(worlds)
+  }
+}
@@ -417,7 +417,7 @@ fn estimate_num_chars(mut num: i32) -> usize {
     num /= 10;
     chars += 1;
   }
-  return chars;
+  chars
 }
 
 /// Pushes `tx` onto `buf`, returning the remaining part of `buf` and whether
@@ -431,5 +431,5 @@ fn push_texel(tx: Texel, buf: &mut &mut [Texel]) -> Option<()> {
   std::mem::swap(buf, &mut tmp);
   tmp = &mut tmp[1..];
   std::mem::swap(buf, &mut tmp);
-  return Some(());
+  Some(())
 }